@@ -0,0 +1,446 @@
+use std::io::{IoResult, MemWriter, Writer};
+use std::mem::transmute;
+
+/// Signature at the start of every precompiled chunk.
+pub static SIGNATURE: &'static [u8] = bytes!("\x1bLua");
+/// Data used to catch conversion errors, at the end of the header.
+pub static TAIL: &'static [u8] = bytes!("\x19\x93\r\n\x1a\n");
+
+pub static VERSION: u8 = 0x52;
+pub static FORMAT: u8 = 0;
+
+/// Header of a precompiled chunk, describing the platform it was produced on.
+#[deriving(Show, Clone)]
+pub struct Header {
+    pub version: u8,
+    pub format: u8,
+    pub little_endian: bool,
+    pub int_size: uint,
+    pub size_t_size: uint,
+    pub instruction_size: uint,
+    pub number_size: uint,
+    /// Whether `lua_Number` is an integral type.
+    pub integral: bool,
+}
+
+/// Constant used by a function.
+#[deriving(Show, Clone)]
+pub enum Constant {
+    ConstNil,
+    ConstBool(bool),
+    ConstNumber(f64),
+    ConstString(Vec<u8>),
+}
+
+/// Description of an upvalue of a function, relative to its enclosing function.
+#[deriving(Show, Clone)]
+pub struct Upvalue {
+    /// Whether the upvalue is a register of the enclosing function, or one of its upvalues.
+    pub in_stack: bool,
+    pub index: uint,
+}
+
+/// Local variable debug information.
+#[deriving(Show, Clone)]
+pub struct LocalVar {
+    pub name: String,
+    /// First instruction where the variable is active.
+    pub start_pc: int,
+    /// First instruction where the variable is dead.
+    pub end_pc: int,
+}
+
+/// Function prototype, as stored in precompiled chunks.
+#[deriving(Show, Clone)]
+pub struct Prototype {
+    pub line_defined: int,
+    pub last_line_defined: int,
+    pub num_params: uint,
+    pub is_vararg: u8,
+    pub max_stack_size: uint,
+    pub code: Vec<u32>,
+    pub constants: Vec<Constant>,
+    pub prototypes: Vec<Prototype>,
+    pub upvalues: Vec<Upvalue>,
+
+    // Debug information
+    pub source: Option<String>,
+    /// Source line of every instruction.
+    pub line_info: Vec<int>,
+    pub local_vars: Vec<LocalVar>,
+    pub upvalue_names: Vec<String>,
+}
+
+impl Prototype {
+    /// Remove debug information from this prototype and its nested prototypes.
+    pub fn strip(&mut self) {
+        self.source = None;
+        self.line_info.clear();
+        self.local_vars.clear();
+        self.upvalue_names.clear();
+
+        for p in self.prototypes.mut_iter() {
+            p.strip();
+        }
+    }
+}
+
+/**
+ *  Precompiled chunk, as produced by `lua_dump` (see `ldump.c` and `lundump.c`).
+ */
+#[deriving(Show, Clone)]
+pub struct Chunk {
+    pub header: Header,
+    pub main: Prototype,
+}
+
+impl Chunk {
+    /// Parse a precompiled chunk.
+    pub fn parse(buf: &[u8]) -> Result<Chunk, String> {
+        let mut parser = Parser {
+            buf: buf,
+            pos: 0,
+            header: None,
+        };
+
+        let header = try!(parser.header());
+        parser.header = Some(header.clone());
+        let main = try!(parser.function());
+
+        Ok(Chunk {
+            header: header,
+            main: main,
+        })
+    }
+
+    /// Remove debug information, like `luac -s`.
+    pub fn strip(&mut self) {
+        self.main.strip();
+    }
+
+    /// Write the chunk in the format expected by `lua_load`.
+    pub fn write<W: Writer>(&self, w: &mut W) -> IoResult<()> {
+        let mut dumper = Dumper {
+            w: w,
+            header: &self.header,
+        };
+
+        try!(dumper.header());
+        dumper.function(&self.main)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = MemWriter::new();
+        self.write(&mut w).unwrap();
+        w.unwrap()
+    }
+}
+
+struct Parser<'a> {
+    buf: &'a [u8],
+    pos: uint,
+    header: Option<Header>,
+}
+
+impl<'a> Parser<'a> {
+    fn bytes(&mut self, n: uint) -> Result<&'a [u8], String> {
+        if self.pos + n > self.buf.len() {
+            return Err("truncated precompiled chunk".to_string());
+        }
+
+        let bytes = self.buf.slice(self.pos, self.pos + n);
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn uint_of_size(&mut self, size: uint) -> Result<u64, String> {
+        let little_endian = self.header.get_ref().little_endian;
+        let bytes = try!(self.bytes(size));
+
+        let mut n = 0u64;
+        for i in range(0, size) {
+            let b = if little_endian { bytes[size - 1 - i] } else { bytes[i] };
+            n = (n << 8) | b as u64;
+        }
+        Ok(n)
+    }
+
+    fn int(&mut self) -> Result<int, String> {
+        let size = self.header.get_ref().int_size;
+        let n = try!(self.uint_of_size(size));
+
+        // Sign-extend.
+        let shift = 64 - size * 8;
+        Ok(((n << shift) as i64 >> shift) as int)
+    }
+
+    fn count(&mut self) -> Result<uint, String> {
+        match try!(self.int()) {
+            n if n < 0 => Err("negative count in precompiled chunk".to_string()),
+            n => Ok(n as uint),
+        }
+    }
+
+    fn number(&mut self) -> Result<f64, String> {
+        let header = self.header.get_ref().clone();
+        let n = try!(self.uint_of_size(header.number_size));
+
+        match (header.number_size, header.integral) {
+            (8, false) => Ok(unsafe { transmute::<u64, f64>(n) }),
+            (4, false) => Ok(unsafe { transmute::<u32, f32>(n as u32) } as f64),
+            (_, true) => Ok(n as i64 as f64),
+            _ => Err(format!("unsupported number size {}", header.number_size)),
+        }
+    }
+
+    fn string(&mut self) -> Result<Option<Vec<u8>>, String> {
+        let size = self.header.get_ref().size_t_size;
+        let len = try!(self.uint_of_size(size)) as uint;
+
+        match len {
+            0 => Ok(None),
+            // Drop trailing NUL.
+            len => self.bytes(len).map(|s| Some(Vec::from_slice(s.slice_to(len - 1)))),
+        }
+    }
+
+    fn debug_string(&mut self) -> Result<Option<String>, String> {
+        let s = try!(self.string());
+        Ok(s.map(|s| String::from_utf8_lossy(s.as_slice()).into_string()))
+    }
+
+    fn header(&mut self) -> Result<Header, String> {
+        if try!(self.bytes(SIGNATURE.len())) != SIGNATURE {
+            return Err("not a precompiled chunk".to_string());
+        }
+
+        let header = Header {
+            version: try!(self.byte()),
+            format: try!(self.byte()),
+            little_endian: try!(self.byte()) == 1,
+            int_size: try!(self.byte()) as uint,
+            size_t_size: try!(self.byte()) as uint,
+            instruction_size: try!(self.byte()) as uint,
+            number_size: try!(self.byte()) as uint,
+            integral: try!(self.byte()) != 0,
+        };
+
+        if header.version != VERSION || header.format != FORMAT {
+            return Err("version mismatch in precompiled chunk".to_string());
+        }
+        if header.instruction_size != 4 || header.int_size > 8 || header.size_t_size > 8 {
+            return Err("incompatible precompiled chunk".to_string());
+        }
+        if try!(self.bytes(TAIL.len())) != TAIL {
+            return Err("corrupted precompiled chunk".to_string());
+        }
+
+        Ok(header)
+    }
+
+    fn function(&mut self) -> Result<Prototype, String> {
+        let line_defined = try!(self.int());
+        let last_line_defined = try!(self.int());
+        let num_params = try!(self.byte()) as uint;
+        let is_vararg = try!(self.byte());
+        let max_stack_size = try!(self.byte()) as uint;
+
+        let n = try!(self.count());
+        let mut code = Vec::with_capacity(n);
+        for _ in range(0, n) {
+            code.push(try!(self.uint_of_size(4)) as u32);
+        }
+
+        let n = try!(self.count());
+        let mut constants = Vec::with_capacity(n);
+        for _ in range(0, n) {
+            let k = match try!(self.byte()) {
+                0 => ConstNil,
+                1 => ConstBool(try!(self.byte()) != 0),
+                3 => ConstNumber(try!(self.number())),
+                4 => ConstString(try!(self.string()).unwrap_or(Vec::new())),
+                ty => return Err(format!("bad constant type {} in precompiled chunk", ty)),
+            };
+            constants.push(k);
+        }
+
+        let n = try!(self.count());
+        let mut prototypes = Vec::with_capacity(n);
+        for _ in range(0, n) {
+            prototypes.push(try!(self.function()));
+        }
+
+        let n = try!(self.count());
+        let mut upvalues = Vec::with_capacity(n);
+        for _ in range(0, n) {
+            upvalues.push(Upvalue {
+                in_stack: try!(self.byte()) != 0,
+                index: try!(self.byte()) as uint,
+            });
+        }
+
+        let source = try!(self.debug_string());
+
+        let n = try!(self.count());
+        let mut line_info = Vec::with_capacity(n);
+        for _ in range(0, n) {
+            line_info.push(try!(self.int()));
+        }
+
+        let n = try!(self.count());
+        let mut local_vars = Vec::with_capacity(n);
+        for _ in range(0, n) {
+            local_vars.push(LocalVar {
+                name: try!(self.debug_string()).unwrap_or(String::new()),
+                start_pc: try!(self.int()),
+                end_pc: try!(self.int()),
+            });
+        }
+
+        let n = try!(self.count());
+        let mut upvalue_names = Vec::with_capacity(n);
+        for _ in range(0, n) {
+            upvalue_names.push(try!(self.debug_string()).unwrap_or(String::new()));
+        }
+
+        Ok(Prototype {
+            line_defined: line_defined,
+            last_line_defined: last_line_defined,
+            num_params: num_params,
+            is_vararg: is_vararg,
+            max_stack_size: max_stack_size,
+            code: code,
+            constants: constants,
+            prototypes: prototypes,
+            upvalues: upvalues,
+            source: source,
+            line_info: line_info,
+            local_vars: local_vars,
+            upvalue_names: upvalue_names,
+        })
+    }
+}
+
+struct Dumper<'a, W> {
+    w: &'a mut W,
+    header: &'a Header,
+}
+
+impl<'a, W: Writer> Dumper<'a, W> {
+    fn uint_of_size(&mut self, n: u64, size: uint) -> IoResult<()> {
+        for i in range(0, size) {
+            let byte = if self.header.little_endian { i } else { size - 1 - i };
+            let shift = byte * 8;
+            try!(self.w.write_u8((n >> shift) as u8));
+        }
+        Ok(())
+    }
+
+    fn int(&mut self, n: int) -> IoResult<()> {
+        let size = self.header.int_size;
+        self.uint_of_size(n as i64 as u64, size)
+    }
+
+    fn number(&mut self, n: f64) -> IoResult<()> {
+        let size = self.header.number_size;
+        let bits = match (size, self.header.integral) {
+            (4, false) => unsafe { transmute::<f32, u32>(n as f32) } as u64,
+            (_, false) => unsafe { transmute::<f64, u64>(n) },
+            (_, true) => n as i64 as u64,
+        };
+        self.uint_of_size(bits, size)
+    }
+
+    fn string(&mut self, s: Option<&[u8]>) -> IoResult<()> {
+        let size = self.header.size_t_size;
+        match s {
+            None => self.uint_of_size(0, size),
+            Some(s) => {
+                // Include trailing NUL.
+                try!(self.uint_of_size(s.len() as u64 + 1, size));
+                try!(self.w.write(s));
+                self.w.write_u8(0)
+            }
+        }
+    }
+
+    fn header(&mut self) -> IoResult<()> {
+        try!(self.w.write(SIGNATURE));
+        try!(self.w.write_u8(self.header.version));
+        try!(self.w.write_u8(self.header.format));
+        try!(self.w.write_u8(if self.header.little_endian { 1 } else { 0 }));
+        try!(self.w.write_u8(self.header.int_size as u8));
+        try!(self.w.write_u8(self.header.size_t_size as u8));
+        try!(self.w.write_u8(self.header.instruction_size as u8));
+        try!(self.w.write_u8(self.header.number_size as u8));
+        try!(self.w.write_u8(if self.header.integral { 1 } else { 0 }));
+        self.w.write(TAIL)
+    }
+
+    fn function(&mut self, f: &Prototype) -> IoResult<()> {
+        try!(self.int(f.line_defined));
+        try!(self.int(f.last_line_defined));
+        try!(self.w.write_u8(f.num_params as u8));
+        try!(self.w.write_u8(f.is_vararg));
+        try!(self.w.write_u8(f.max_stack_size as u8));
+
+        try!(self.int(f.code.len() as int));
+        for &i in f.code.iter() {
+            try!(self.uint_of_size(i as u64, 4));
+        }
+
+        try!(self.int(f.constants.len() as int));
+        for k in f.constants.iter() {
+            match *k {
+                ConstNil => try!(self.w.write_u8(0)),
+                ConstBool(b) => {
+                    try!(self.w.write_u8(1));
+                    try!(self.w.write_u8(if b { 1 } else { 0 }));
+                }
+                ConstNumber(n) => {
+                    try!(self.w.write_u8(3));
+                    try!(self.number(n));
+                }
+                ConstString(ref s) => {
+                    try!(self.w.write_u8(4));
+                    try!(self.string(Some(s.as_slice())));
+                }
+            }
+        }
+
+        try!(self.int(f.prototypes.len() as int));
+        for p in f.prototypes.iter() {
+            try!(self.function(p));
+        }
+
+        try!(self.int(f.upvalues.len() as int));
+        for upval in f.upvalues.iter() {
+            try!(self.w.write_u8(if upval.in_stack { 1 } else { 0 }));
+            try!(self.w.write_u8(upval.index as u8));
+        }
+
+        try!(self.string(f.source.as_ref().map(|s| s.as_bytes())));
+
+        try!(self.int(f.line_info.len() as int));
+        for &line in f.line_info.iter() {
+            try!(self.int(line));
+        }
+
+        try!(self.int(f.local_vars.len() as int));
+        for var in f.local_vars.iter() {
+            try!(self.string(Some(var.name.as_bytes())));
+            try!(self.int(var.start_pc));
+            try!(self.int(var.end_pc));
+        }
+
+        try!(self.int(f.upvalue_names.len() as int));
+        for name in f.upvalue_names.iter() {
+            try!(self.string(Some(name.as_bytes())));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+use std::cell::RefCell;
+use std::hash::hash;
+use std::io;
+use std::io::File;
+use std::io::fs;
+use collections::hashmap::HashMap;
+
+use libc;
+
+/// Where compiled chunks are stored.
+pub enum CacheStorage {
+    MemoryStorage,
+    /// One file per chunk, in the given directory.
+    DiskStorage(Path),
+}
+
+/**
+ *  Cache of compiled chunks, keyed by a hash of their source and chunk name.
+ *
+ *  Once set on a `Lua`, scripts run by `exec_file` are loaded from their cached bytecode
+ *  when their source didn't change, skipping parsing.
+ */
+pub struct ChunkCache {
+    storage: CacheStorage,
+    memory: RefCell<HashMap<u64, Vec<u8>>>,
+}
+
+impl ChunkCache {
+    pub fn new(storage: CacheStorage) -> ChunkCache {
+        ChunkCache {
+            storage: storage,
+            memory: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Create a cache which keeps compiled chunks in memory.
+    pub fn in_memory() -> ChunkCache {
+        ChunkCache::new(MemoryStorage)
+    }
+
+    /// Create a cache which stores compiled chunks in a directory, created if needed.
+    pub fn on_disk(dir: Path) -> ChunkCache {
+        ChunkCache::new(DiskStorage(dir))
+    }
+
+    /// Compute the cache key of a chunk.
+    pub fn key(source: &[u8], chunkname: &str) -> u64 {
+        hash(&(source, chunkname))
+    }
+
+    /// Get the compiled chunk stored under `key`, if any.
+    pub fn get(&self, key: u64) -> Option<Vec<u8>> {
+        match self.storage {
+            MemoryStorage => self.memory.borrow().find(&key).map(|bytes| bytes.clone()),
+            DiskStorage(ref dir) => File::open(&chunk_path(dir, key)).read_to_end().ok(),
+        }
+    }
+
+    /// Store a compiled chunk under `key`.
+    pub fn put(&self, key: u64, bytes: Vec<u8>) {
+        match self.storage {
+            MemoryStorage => {
+                self.memory.borrow_mut().insert(key, bytes);
+            }
+            DiskStorage(ref dir) => {
+                // The cache is only an optimization, failing to fill it isn't an error.
+                if !dir.exists() {
+                    let _ = fs::mkdir_recursive(dir, io::UserRWX);
+                }
+                // Written to a file of this process, then renamed: a crash or another process never
+                // leaves a truncated chunk in place.
+                let tmp = dir.join(format!("{:016x}.{}.tmp", key, unsafe { libc::getpid() }));
+                let written = File::create(&tmp).and_then(|mut file| {
+                    file.write(bytes.as_slice()).and_then(|()| file.fsync())
+                });
+                let renamed = written.and_then(|()| fs::rename(&tmp, &chunk_path(dir, key)));
+                if renamed.is_err() {
+                    let _ = fs::unlink(&tmp);
+                }
+            }
+        }
+    }
+
+    /// Remove every stored chunk.
+    pub fn clear(&self) {
+        match self.storage {
+            MemoryStorage => self.memory.borrow_mut().clear(),
+            DiskStorage(ref dir) => {
+                let _ = fs::rmdir_recursive(dir);
+            }
+        }
+    }
+}
+
+fn chunk_path(dir: &Path, key: u64) -> Path {
+    dir.join(format!("{:016x}.luac", key))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{File, TempDir};
+    use std::io::fs;
+
+    use lua::Lua;
+
+    use super::ChunkCache;
+
+    /// Run a script through the cache, returning its global `x`.
+    fn run(cache: &ChunkCache, script: &Path) -> Option<int> {
+        let lua = Lua::new();
+        lua.set_chunk_cache(cache);
+        assert_eq!(lua.exec_file(script.as_str().unwrap()), ::status::LuaOk);
+        lua.get_global("x")
+    }
+
+    /// Compile a chunk.
+    fn compile(source: &str) -> Vec<u8> {
+        let lua = Lua::new();
+        assert_eq!(lua.state.load_str(source), ::status::LuaOk);
+        lua.dump(false).unwrap()
+    }
+
+    /// Check that scripts are loaded from the cache until they change, then invalidated.
+    fn check_cache(cache: &ChunkCache, dir: &Path) {
+        let script = dir.join("script.lua");
+        let chunkname = format!("@{}", script.as_str().unwrap());
+        // BOM and shebang, skipped like `luaL_loadfile` keeping line numbers.
+        File::create(&script).write(bytes!(0xEF, 0xBB, 0xBF, "#!/usr/bin/env rlua\nx = 1")).unwrap();
+        let key = ChunkCache::key(bytes!("\nx = 1"), chunkname.as_slice());
+
+        assert_eq!(run(cache, &script), Some(1));
+        assert!(cache.get(key).is_some());
+
+        // Hit: the cached chunk is used while the script doesn't change.
+        cache.put(key, compile("x = 2"));
+        assert_eq!(run(cache, &script), Some(2));
+
+        // A truncated chunk is compiled again.
+        let truncated = Vec::from_slice(compile("x = 2").slice_to(10));
+        cache.put(key, truncated);
+        assert_eq!(run(cache, &script), Some(1));
+
+        // Invalidation: the script changed.
+        File::create(&script).write(bytes!("x = 3")).unwrap();
+        assert_eq!(run(cache, &script), Some(3));
+        assert!(cache.get(ChunkCache::key(bytes!("x = 3"), chunkname.as_slice())).is_some());
+    }
+
+    #[test]
+    fn test_memory_cache()
+    {
+        let dir = TempDir::new("chunk-cache").unwrap();
+        let cache = ChunkCache::in_memory();
+        check_cache(&cache, dir.path());
+    }
+
+    #[test]
+    fn test_disk_cache()
+    {
+        let dir = TempDir::new("chunk-cache").unwrap();
+        let cache_dir = dir.path().join("cache");
+        let cache = ChunkCache::on_disk(cache_dir.clone());
+        check_cache(&cache, dir.path());
+
+        // Only complete chunks are left, for `x = 1` and `x = 3`.
+        let files = fs::readdir(&cache_dir).unwrap();
+        assert_eq!(files.len(), 2);
+        assert!(files.iter().all(|path| path.extension_str() == Some("luac")));
+
+        cache.clear();
+        assert!(!cache_dir.exists());
+    }
+}
@@ -0,0 +1,585 @@
+use std::{uint, u64, u32, u16, u8, int, i64, i32, i16, i8};
+use serialize::{Encoder, Decoder};
+
+use state::State;
+use types::*;
+
+/// Field holding the variant name of enums encoded as tables.
+pub static TAG: &'static str = "tag";
+
+#[deriving(Show, Clone, Eq)]
+pub enum CodecError {
+    /**
+     *  A value of the first type was expected, the second one was found (or the number found,
+     *  when it doesn't fit in the expected integer type).
+     */
+    ExpectedError(String, String),
+    MissingFieldError(String),
+    UnknownVariantError(String),
+    /// Map key which can't be used as a table key, i.e. nil or NaN.
+    InvalidKeyError,
+    /// Value nested too deeply to fit on the Lua stack.
+    StackOverflowError,
+}
+
+pub type EncodeResult = Result<(), CodecError>;
+pub type DecodeResult<T> = Result<T, CodecError>;
+
+/**
+ *  Encoder pushing Rust values built with `#[deriving(Encodable)]` onto the Lua stack.
+ *
+ *  Structs and maps become tables with named keys, sequences and tuples become arrays
+ *  and `None` becomes nil.
+ *  Enum variants without arguments become strings, the other ones tables holding
+ *  the variant name in the `tag` field and the arguments in the array part (or named
+ *  fields for struct variants).
+ */
+pub struct LuaEncoder<'a, 'b> {
+    state: &'a State<'b>,
+}
+
+impl<'a, 'b> LuaEncoder<'a, 'b> {
+    pub fn new(state: &'a State<'b>) -> LuaEncoder<'a, 'b> {
+        LuaEncoder {
+            state: state,
+        }
+    }
+
+    /// Push a new table, making room for its fields.
+    fn push_table(&mut self, narr: uint, nrec: uint) -> EncodeResult {
+        if !self.state.check_stack(3) {
+            return Err(StackOverflowError);
+        }
+        self.state.create_table(narr as int, nrec as int);
+        Ok(())
+    }
+
+    fn push_number(&mut self, n: f64) -> EncodeResult {
+        self.state.push_float(n);
+        Ok(())
+    }
+}
+
+impl<'a, 'b> Encoder<CodecError> for LuaEncoder<'a, 'b> {
+    fn emit_nil(&mut self) -> EncodeResult {
+        self.state.push_nil();
+        Ok(())
+    }
+
+    fn emit_uint(&mut self, v: uint) -> EncodeResult { self.push_number(v as f64) }
+    fn emit_u64(&mut self, v: u64) -> EncodeResult { self.push_number(v as f64) }
+    fn emit_u32(&mut self, v: u32) -> EncodeResult { self.push_number(v as f64) }
+    fn emit_u16(&mut self, v: u16) -> EncodeResult { self.push_number(v as f64) }
+    fn emit_u8(&mut self, v: u8) -> EncodeResult { self.push_number(v as f64) }
+    fn emit_int(&mut self, v: int) -> EncodeResult { self.push_number(v as f64) }
+    fn emit_i64(&mut self, v: i64) -> EncodeResult { self.push_number(v as f64) }
+    fn emit_i32(&mut self, v: i32) -> EncodeResult { self.push_number(v as f64) }
+    fn emit_i16(&mut self, v: i16) -> EncodeResult { self.push_number(v as f64) }
+    fn emit_i8(&mut self, v: i8) -> EncodeResult { self.push_number(v as f64) }
+    fn emit_f64(&mut self, v: f64) -> EncodeResult { self.push_number(v) }
+    fn emit_f32(&mut self, v: f32) -> EncodeResult { self.push_number(v as f64) }
+
+    fn emit_bool(&mut self, v: bool) -> EncodeResult {
+        self.state.push_bool(v);
+        Ok(())
+    }
+
+    fn emit_char(&mut self, v: char) -> EncodeResult {
+        self.state.push_str(String::from_char(1, v).as_slice());
+        Ok(())
+    }
+
+    fn emit_str(&mut self, v: &str) -> EncodeResult {
+        self.state.push_str(v);
+        Ok(())
+    }
+
+    fn emit_enum(&mut self, _name: &str, f: |&mut LuaEncoder<'a, 'b>| -> EncodeResult) -> EncodeResult {
+        f(self)
+    }
+
+    fn emit_enum_variant(&mut self, v_name: &str, _v_id: uint, len: uint,
+                         f: |&mut LuaEncoder<'a, 'b>| -> EncodeResult) -> EncodeResult {
+        if len == 0 {
+            self.state.push_str(v_name);
+            return Ok(());
+        }
+
+        try!(self.push_table(len, 1));
+        self.state.push_str(v_name);
+        self.state.set_field(-2, TAG);
+        f(self)
+    }
+
+    fn emit_enum_variant_arg(&mut self, a_idx: uint, f: |&mut LuaEncoder<'a, 'b>| -> EncodeResult) -> EncodeResult {
+        try!(f(self));
+        self.state.raw_set_index(-2, (a_idx + 1) as int);
+        Ok(())
+    }
+
+    fn emit_enum_struct_variant(&mut self, v_name: &str, v_id: uint, len: uint,
+                                f: |&mut LuaEncoder<'a, 'b>| -> EncodeResult) -> EncodeResult {
+        self.emit_enum_variant(v_name, v_id, len, f)
+    }
+
+    fn emit_enum_struct_variant_field(&mut self, f_name: &str, _f_idx: uint,
+                                      f: |&mut LuaEncoder<'a, 'b>| -> EncodeResult) -> EncodeResult {
+        try!(f(self));
+        self.state.set_field(-2, f_name);
+        Ok(())
+    }
+
+    fn emit_struct(&mut self, _name: &str, len: uint, f: |&mut LuaEncoder<'a, 'b>| -> EncodeResult) -> EncodeResult {
+        try!(self.push_table(0, len));
+        f(self)
+    }
+
+    fn emit_struct_field(&mut self, f_name: &str, _f_idx: uint,
+                         f: |&mut LuaEncoder<'a, 'b>| -> EncodeResult) -> EncodeResult {
+        try!(f(self));
+        self.state.set_field(-2, f_name);
+        Ok(())
+    }
+
+    fn emit_tuple(&mut self, len: uint, f: |&mut LuaEncoder<'a, 'b>| -> EncodeResult) -> EncodeResult {
+        self.emit_seq(len, f)
+    }
+
+    fn emit_tuple_arg(&mut self, idx: uint, f: |&mut LuaEncoder<'a, 'b>| -> EncodeResult) -> EncodeResult {
+        self.emit_seq_elt(idx, f)
+    }
+
+    fn emit_tuple_struct(&mut self, _name: &str, len: uint,
+                         f: |&mut LuaEncoder<'a, 'b>| -> EncodeResult) -> EncodeResult {
+        self.emit_seq(len, f)
+    }
+
+    fn emit_tuple_struct_arg(&mut self, f_idx: uint, f: |&mut LuaEncoder<'a, 'b>| -> EncodeResult) -> EncodeResult {
+        self.emit_seq_elt(f_idx, f)
+    }
+
+    fn emit_option(&mut self, f: |&mut LuaEncoder<'a, 'b>| -> EncodeResult) -> EncodeResult {
+        f(self)
+    }
+
+    fn emit_option_none(&mut self) -> EncodeResult {
+        self.state.push_nil();
+        Ok(())
+    }
+
+    fn emit_option_some(&mut self, f: |&mut LuaEncoder<'a, 'b>| -> EncodeResult) -> EncodeResult {
+        f(self)
+    }
+
+    fn emit_seq(&mut self, len: uint, f: |&mut LuaEncoder<'a, 'b>| -> EncodeResult) -> EncodeResult {
+        try!(self.push_table(len, 0));
+        f(self)
+    }
+
+    fn emit_seq_elt(&mut self, idx: uint, f: |&mut LuaEncoder<'a, 'b>| -> EncodeResult) -> EncodeResult {
+        try!(f(self));
+        self.state.raw_set_index(-2, (idx + 1) as int);
+        Ok(())
+    }
+
+    fn emit_map(&mut self, len: uint, f: |&mut LuaEncoder<'a, 'b>| -> EncodeResult) -> EncodeResult {
+        try!(self.push_table(0, len));
+        f(self)
+    }
+
+    fn emit_map_elt_key(&mut self, _idx: uint, f: |&mut LuaEncoder<'a, 'b>| -> EncodeResult) -> EncodeResult {
+        f(self)
+    }
+
+    fn emit_map_elt_val(&mut self, _idx: uint, f: |&mut LuaEncoder<'a, 'b>| -> EncodeResult) -> EncodeResult {
+        // Setting a nil or NaN key raises a Lua error, which can't be caught here.
+        match self.state.get_type(-1) {
+            LuaNil => return Err(InvalidKeyError),
+            LuaNumber if self.state.get_float(-1).is_nan() => return Err(InvalidKeyError),
+            _ => {}
+        }
+        try!(f(self));
+        self.state.raw_set(-3);
+        Ok(())
+    }
+}
+
+/**
+ *  Decoder reading Rust values built with `#[deriving(Decodable)]` from the value at the top
+ *  of the Lua stack, expecting the layout produced by `LuaEncoder`.
+ *
+ *  Missing struct fields are decoded as nil, so optional fields can be omitted.
+ */
+pub struct LuaDecoder<'a, 'b> {
+    state: &'a State<'b>,
+}
+
+impl<'a, 'b> LuaDecoder<'a, 'b> {
+    pub fn new(state: &'a State<'b>) -> LuaDecoder<'a, 'b> {
+        LuaDecoder {
+            state: state,
+        }
+    }
+
+    fn expected<T>(&self, expected: &str) -> DecodeResult<T> {
+        Err(ExpectedError(expected.to_string(), format!("{}", self.state.get_type(-1))))
+    }
+
+    fn read_number(&mut self) -> DecodeResult<f64> {
+        match self.state.get_type(-1) {
+            LuaNumber => Ok(self.state.get_float(-1)),
+            _ => self.expected("Number"),
+        }
+    }
+
+    /// Read an integral number between `min` and `max`, the bounds of the integer type `name`.
+    fn read_integer(&mut self, min: f64, max: f64, name: &str) -> DecodeResult<f64> {
+        let n = try!(self.read_number());
+        // The maximum of 64 bit types is rounded up to the next power of 2, which doesn't fit.
+        if n != n.floor() || n < min || n >= max + 1.0 {
+            return Err(ExpectedError(name.to_string(), n.to_string()));
+        }
+        Ok(n)
+    }
+
+    /// Check that the value is a table, making room for its fields.
+    fn expect_table(&mut self) -> DecodeResult<()> {
+        match self.state.get_type(-1) {
+            LuaTable => {}
+            _ => return self.expected("Table"),
+        }
+        match self.state.check_stack(4) {
+            true => Ok(()),
+            false => Err(StackOverflowError),
+        }
+    }
+
+    /// Decode the value pushed by the caller, then pop it.
+    fn read_pushed<T>(&mut self, f: |&mut LuaDecoder<'a, 'b>| -> DecodeResult<T>) -> DecodeResult<T> {
+        let result = f(self);
+        self.state.pop(1);
+        result
+    }
+}
+
+impl<'a, 'b> Decoder<CodecError> for LuaDecoder<'a, 'b> {
+    fn read_nil(&mut self) -> DecodeResult<()> {
+        match self.state.get_type(-1) {
+            LuaNone | LuaNil => Ok(()),
+            _ => self.expected("Nil"),
+        }
+    }
+
+    fn read_uint(&mut self) -> DecodeResult<uint> {
+        self.read_integer(uint::MIN as f64, uint::MAX as f64, "uint").map(|n| n as uint)
+    }
+    fn read_u64(&mut self) -> DecodeResult<u64> {
+        self.read_integer(u64::MIN as f64, u64::MAX as f64, "u64").map(|n| n as u64)
+    }
+    fn read_u32(&mut self) -> DecodeResult<u32> {
+        self.read_integer(u32::MIN as f64, u32::MAX as f64, "u32").map(|n| n as u32)
+    }
+    fn read_u16(&mut self) -> DecodeResult<u16> {
+        self.read_integer(u16::MIN as f64, u16::MAX as f64, "u16").map(|n| n as u16)
+    }
+    fn read_u8(&mut self) -> DecodeResult<u8> {
+        self.read_integer(u8::MIN as f64, u8::MAX as f64, "u8").map(|n| n as u8)
+    }
+    fn read_int(&mut self) -> DecodeResult<int> {
+        self.read_integer(int::MIN as f64, int::MAX as f64, "int").map(|n| n as int)
+    }
+    fn read_i64(&mut self) -> DecodeResult<i64> {
+        self.read_integer(i64::MIN as f64, i64::MAX as f64, "i64").map(|n| n as i64)
+    }
+    fn read_i32(&mut self) -> DecodeResult<i32> {
+        self.read_integer(i32::MIN as f64, i32::MAX as f64, "i32").map(|n| n as i32)
+    }
+    fn read_i16(&mut self) -> DecodeResult<i16> {
+        self.read_integer(i16::MIN as f64, i16::MAX as f64, "i16").map(|n| n as i16)
+    }
+    fn read_i8(&mut self) -> DecodeResult<i8> {
+        self.read_integer(i8::MIN as f64, i8::MAX as f64, "i8").map(|n| n as i8)
+    }
+    fn read_f64(&mut self) -> DecodeResult<f64> { self.read_number() }
+    fn read_f32(&mut self) -> DecodeResult<f32> { self.read_number().map(|n| n as f32) }
+
+    fn read_bool(&mut self) -> DecodeResult<bool> {
+        match self.state.get_type(-1) {
+            LuaBoolean => Ok(self.state.get_bool(-1)),
+            _ => self.expected("Boolean"),
+        }
+    }
+
+    fn read_char(&mut self) -> DecodeResult<char> {
+        let s = try!(self.read_str());
+        match s.as_slice().char_len() {
+            1 => Ok(s.as_slice().char_at(0)),
+            _ => self.expected("Character"),
+        }
+    }
+
+    fn read_str(&mut self) -> DecodeResult<String> {
+        match self.state.get_type(-1) {
+            LuaString => Ok(self.state.get_str(-1)),
+            _ => self.expected("String"),
+        }
+    }
+
+    fn read_enum<T>(&mut self, _name: &str, f: |&mut LuaDecoder<'a, 'b>| -> DecodeResult<T>) -> DecodeResult<T> {
+        f(self)
+    }
+
+    fn read_enum_variant<T>(&mut self, names: &[&str],
+                            f: |&mut LuaDecoder<'a, 'b>, uint| -> DecodeResult<T>) -> DecodeResult<T> {
+        let name = match self.state.get_type(-1) {
+            LuaString => self.state.get_str(-1),
+            LuaTable => {
+                try!(self.expect_table());
+                self.state.get_field(-1, TAG);
+                let tag = self.read_pushed(|d| d.read_str());
+                match tag {
+                    Ok(tag) => tag,
+                    Err(_) => return Err(MissingFieldError(TAG.to_string())),
+                }
+            }
+            _ => return self.expected("String or Table"),
+        };
+
+        match names.iter().position(|n| *n == name.as_slice()) {
+            Some(idx) => f(self, idx),
+            None => Err(UnknownVariantError(name)),
+        }
+    }
+
+    fn read_enum_variant_arg<T>(&mut self, a_idx: uint, f: |&mut LuaDecoder<'a, 'b>| -> DecodeResult<T>) -> DecodeResult<T> {
+        self.state.raw_get_index(-1, (a_idx + 1) as int);
+        self.read_pushed(f)
+    }
+
+    fn read_enum_struct_variant<T>(&mut self, names: &[&str],
+                                   f: |&mut LuaDecoder<'a, 'b>, uint| -> DecodeResult<T>) -> DecodeResult<T> {
+        self.read_enum_variant(names, f)
+    }
+
+    fn read_enum_struct_variant_field<T>(&mut self, f_name: &str, f_idx: uint,
+                                         f: |&mut LuaDecoder<'a, 'b>| -> DecodeResult<T>) -> DecodeResult<T> {
+        self.read_struct_field(f_name, f_idx, f)
+    }
+
+    fn read_struct<T>(&mut self, _s_name: &str, _len: uint,
+                      f: |&mut LuaDecoder<'a, 'b>| -> DecodeResult<T>) -> DecodeResult<T> {
+        try!(self.expect_table());
+        f(self)
+    }
+
+    fn read_struct_field<T>(&mut self, f_name: &str, _f_idx: uint,
+                            f: |&mut LuaDecoder<'a, 'b>| -> DecodeResult<T>) -> DecodeResult<T> {
+        self.state.get_field(-1, f_name);
+        let missing = match self.state.get_type(-1) {
+            LuaNil => true,
+            _ => false,
+        };
+        match self.read_pushed(f) {
+            Err(ExpectedError(..)) if missing => Err(MissingFieldError(f_name.to_string())),
+            result => result,
+        }
+    }
+
+    fn read_tuple<T>(&mut self, f: |&mut LuaDecoder<'a, 'b>, uint| -> DecodeResult<T>) -> DecodeResult<T> {
+        self.read_seq(f)
+    }
+
+    fn read_tuple_arg<T>(&mut self, a_idx: uint, f: |&mut LuaDecoder<'a, 'b>| -> DecodeResult<T>) -> DecodeResult<T> {
+        self.read_seq_elt(a_idx, f)
+    }
+
+    fn read_tuple_struct<T>(&mut self, _s_name: &str,
+                            f: |&mut LuaDecoder<'a, 'b>, uint| -> DecodeResult<T>) -> DecodeResult<T> {
+        self.read_seq(f)
+    }
+
+    fn read_tuple_struct_arg<T>(&mut self, a_idx: uint,
+                                f: |&mut LuaDecoder<'a, 'b>| -> DecodeResult<T>) -> DecodeResult<T> {
+        self.read_seq_elt(a_idx, f)
+    }
+
+    fn read_option<T>(&mut self, f: |&mut LuaDecoder<'a, 'b>, bool| -> DecodeResult<T>) -> DecodeResult<T> {
+        match self.state.get_type(-1) {
+            LuaNone | LuaNil => f(self, false),
+            _ => f(self, true),
+        }
+    }
+
+    fn read_seq<T>(&mut self, f: |&mut LuaDecoder<'a, 'b>, uint| -> DecodeResult<T>) -> DecodeResult<T> {
+        try!(self.expect_table());
+        let len = self.state.raw_len(-1);
+        f(self, len)
+    }
+
+    fn read_seq_elt<T>(&mut self, idx: uint, f: |&mut LuaDecoder<'a, 'b>| -> DecodeResult<T>) -> DecodeResult<T> {
+        self.state.raw_get_index(-1, (idx + 1) as int);
+        self.read_pushed(f)
+    }
+
+    fn read_map<T>(&mut self, f: |&mut LuaDecoder<'a, 'b>, uint| -> DecodeResult<T>) -> DecodeResult<T> {
+        try!(self.expect_table());
+
+        // Keys can't be accessed by position, so collect them in an array first.
+        self.state.new_table();
+        let mut len = 0;
+        self.state.push_nil();
+        while self.state.next(-3) {
+            self.state.pop(1);
+            self.state.push_value(-1);
+            len += 1;
+            self.state.raw_set_index(-3, len);
+        }
+
+        let result = f(self, len as uint);
+        self.state.pop(1);
+        result
+    }
+
+    fn read_map_elt_key<T>(&mut self, idx: uint, f: |&mut LuaDecoder<'a, 'b>| -> DecodeResult<T>) -> DecodeResult<T> {
+        self.state.raw_get_index(-1, (idx + 1) as int);
+        self.read_pushed(f)
+    }
+
+    fn read_map_elt_val<T>(&mut self, idx: uint, f: |&mut LuaDecoder<'a, 'b>| -> DecodeResult<T>) -> DecodeResult<T> {
+        // The stack holds the map, then its keys.
+        self.state.raw_get_index(-1, (idx + 1) as int);
+        self.state.raw_get(-3);
+        self.read_pushed(f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use lua::Lua;
+    use status::LuaOk;
+
+    use serialize::Encoder;
+
+    use super::{CodecError, ExpectedError, MissingFieldError, InvalidKeyError, LuaEncoder};
+
+    #[deriving(Encodable, Decodable, Eq, Show)]
+    enum Shape {
+        Point,
+        Circle(f64),
+    }
+
+    #[deriving(Encodable, Decodable, Eq, Show)]
+    struct Config {
+        name: String,
+        size: uint,
+        tags: Vec<String>,
+        shapes: Vec<Shape>,
+        comment: Option<String>,
+    }
+
+    #[test]
+    fn test_codec()
+    {
+        let lua = Lua::new();
+        let config = Config {
+            name: "test".to_string(),
+            size: 3,
+            tags: vec!["a".to_string(), "b".to_string()],
+            shapes: vec![Point, Circle(2.0)],
+            comment: None,
+        };
+        assert_eq!(lua.push_encodable(&config), Ok(()));
+        lua.state.set_global("config");
+        assert_eq!(lua.exec_str("
+            assert(config.name == 'test' and config.size == 3)
+            assert(#config.tags == 2 and config.tags[2] == 'b')
+            assert(config.shapes[1] == 'Point')
+            assert(config.shapes[2].tag == 'Circle' and config.shapes[2][1] == 2)
+            config.comment = 'hello'
+        "), LuaOk);
+
+        lua.state.get_global("config");
+        let decoded: Config = lua.get_decodable(-1).unwrap();
+        assert_eq!(decoded, Config { comment: Some("hello".to_string()), ..config });
+
+        assert_eq!(lua.exec_str("config.size = nil"), LuaOk);
+        lua.state.get_global("config");
+        let missing: Result<Config, CodecError> = lua.get_decodable(-1);
+        assert_eq!(missing, Err(MissingFieldError("size".to_string())));
+    }
+
+
+    #[test]
+    fn test_codec_integers()
+    {
+        let lua = Lua::new();
+        for &n in [255.0, 0.0].iter() {
+            lua.state.push_float(n);
+            let byte: Result<u8, CodecError> = lua.get_decodable(-1);
+            assert_eq!(byte, Ok(n as u8));
+            lua.state.pop(1);
+        }
+        for &n in [256.0, -1.0, 1.5, 0.0 / 0.0].iter() {
+            lua.state.push_float(n);
+            let byte: Result<u8, CodecError> = lua.get_decodable(-1);
+            assert!(byte.is_err());
+            lua.state.pop(1);
+        }
+
+        lua.state.push_float(-1.0);
+        let unsigned: Result<uint, CodecError> = lua.get_decodable(-1);
+        assert_eq!(unsigned, Err(ExpectedError("uint".to_string(), "-1".to_string())));
+        let signed: Result<int, CodecError> = lua.get_decodable(-1);
+        assert_eq!(signed, Ok(-1));
+        lua.state.pop(1);
+
+        // 2^63 doesn't fit in an i64, 2^63 - 1024 (the previous double) does.
+        lua.state.push_float(9223372036854775808.0);
+        let big: Result<i64, CodecError> = lua.get_decodable(-1);
+        assert!(big.is_err());
+        let big: Result<u64, CodecError> = lua.get_decodable(-1);
+        assert_eq!(big, Ok(9223372036854775808));
+        lua.state.pop(1);
+        lua.state.push_float(9223372036854774784.0);
+        let big: Result<i64, CodecError> = lua.get_decodable(-1);
+        assert_eq!(big, Ok(9223372036854774784));
+        lua.state.pop(1);
+    }
+
+    #[deriving(Encodable, Decodable, Eq, Show)]
+    struct Nested {
+        inner: Option<Box<Nested>>,
+    }
+
+    #[test]
+    fn test_codec_nesting()
+    {
+        let lua = Lua::new();
+        let mut nested = Nested { inner: None };
+        for _ in range(0, 1000) {
+            nested = Nested { inner: Some(box nested) };
+        }
+
+        // Deeper than the stack space guaranteed to C functions.
+        assert_eq!(lua.push_encodable(&nested), Ok(()));
+        let decoded: Result<Nested, CodecError> = lua.get_decodable(-1);
+        assert_eq!(decoded, Ok(nested));
+        lua.state.pop(1);
+    }
+
+    #[test]
+    fn test_codec_invalid_key()
+    {
+        let lua = Lua::new();
+        let top = lua.state.get_top();
+        let result = {
+            let mut encoder = LuaEncoder::new(&lua.state);
+            encoder.emit_map(1, |e| {
+                try!(e.emit_map_elt_key(0, |e| e.emit_f64(0.0 / 0.0)));
+                e.emit_map_elt_val(0, |e| e.emit_bool(true))
+            })
+        };
+        assert_eq!(result, Err(InvalidKeyError));
+        lua.state.set_top(top);
+    }
+}
@@ -0,0 +1,309 @@
+use std::cell::RefCell;
+use std::io::{IoResult, Writer};
+use collections::hashmap::HashSet;
+use collections::treemap::TreeMap;
+
+use libc::c_void;
+use time;
+
+use lua::Lua;
+use bytecode::{Chunk, Prototype};
+use debug::{
+    ActivationRecord,
+    DebugEvent,
+    HookMask,
+    HookCall,
+    HookTailCall,
+    HookLine,
+    MASK_CALL,
+    MASK_LINE,
+};
+use types::LuaNumber;
+
+/// Line coverage of a single source file.
+#[deriving(Show, Clone)]
+pub struct FileCoverage {
+    /// Hit count of every active line.
+    pub lines: TreeMap<int, uint>,
+}
+
+impl FileCoverage {
+    fn new() -> FileCoverage {
+        FileCoverage {
+            lines: TreeMap::new(),
+        }
+    }
+
+    /// Record an active line, not executed yet.
+    fn add_line(&mut self, line: int) {
+        if !self.lines.contains_key(&line) {
+            self.lines.insert(line, 0);
+        }
+    }
+
+    /// Get the number of active lines.
+    pub fn lines_found(&self) -> uint {
+        self.lines.len()
+    }
+
+    /// Get the number of executed lines.
+    pub fn lines_hit(&self) -> uint {
+        self.lines.values().filter(|&&hits| hits > 0).count()
+    }
+}
+
+/**
+ *  Line coverage collector, built on debug hooks.
+ *
+ *  Only functions loaded from files are recorded. Active lines of every function of a chunk are
+ *  recorded when its main function is called, so functions which never run count as missed lines.
+ *  Functions of chunks started before the collector was set are recorded when called.
+ */
+pub struct Coverage {
+    files: RefCell<TreeMap<String, FileCoverage>>,
+    /// Recorded functions, by address: functions of a file may be defined on the same lines.
+    functions: RefCell<HashSet<*c_void>>,
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage {
+            files: RefCell::new(TreeMap::new()),
+            functions: RefCell::new(HashSet::new()),
+        }
+    }
+
+    /// Hook mask needed by the collector.
+    pub fn hook_mask(&self) -> HookMask {
+        MASK_CALL | MASK_LINE
+    }
+
+    /// Handle a debug hook event.
+    pub fn on_event(&self, lua: &Lua, event: DebugEvent, ar: &mut ActivationRecord) {
+        match event {
+            HookCall | HookTailCall => {
+                ar.get_info(&lua.state, "S");
+                self.add_function(lua, ar);
+            }
+            HookLine(line) => {
+                ar.get_info(&lua.state, "S");
+                // The function may have been called before the hook was set.
+                let filename = match self.add_function(lua, ar) {
+                    Some(filename) => filename,
+                    None => return,
+                };
+
+                let mut files = self.files.borrow_mut();
+                let file = files.find_mut(&filename).unwrap();
+                match file.lines.find_mut(&line) {
+                    Some(hits) => {
+                        *hits += 1;
+                        return;
+                    }
+                    None => {}
+                }
+                file.lines.insert(line, 1);
+            }
+            _ => {}
+        }
+    }
+
+    /**
+     *  Record the active lines of the function of `ar`, and of its nested functions for a main
+     *  chunk, if not already done.
+     *
+     *  Return the function filename, or None if it wasn't loaded from a file.
+     */
+    fn add_function(&self, lua: &Lua, ar: &mut ActivationRecord) -> Option<String> {
+        let filename = match ar.source() {
+            Some(ref source) if source.as_slice().starts_with("@") => source.as_slice().slice_from(1).to_string(),
+            _ => return None,
+        };
+
+        ar.get_info(&lua.state, "f");
+        let function = lua.state.to_pointer(-1);
+        lua.state.pop(1);
+        if !self.functions.borrow_mut().insert(function) {
+            return Some(filename);
+        }
+
+        let mut files = self.files.borrow_mut();
+        if !files.contains_key(&filename) {
+            files.insert(filename.clone(), FileCoverage::new());
+        }
+        let file = files.find_mut(&filename).unwrap();
+
+        let main = match ar.what() {
+            Some(ref what) => what.as_slice() == "main",
+            None => false,
+        };
+        if main {
+            // Walk the prototypes of the dumped chunk.
+            ar.get_info(&lua.state, "f");
+            let chunk = lua.dump(false).ok().and_then(|bytes| Chunk::parse(bytes.as_slice()).ok());
+            lua.state.pop(1);
+            match chunk {
+                Some(chunk) => {
+                    add_prototype(file, &chunk.main);
+                    return Some(filename);
+                }
+                None => {}
+            }
+        }
+
+        // Push a table whose keys are the active lines.
+        ar.get_info(&lua.state, "L");
+        lua.state.push_nil();
+        while lua.state.next(-2) {
+            match lua.state.get_type(-2) {
+                LuaNumber => file.add_line(lua.state.get_int(-2)),
+                _ => {}
+            }
+            lua.state.pop(1);
+        }
+        lua.state.pop(1);
+
+        Some(filename)
+    }
+
+    /// Get the coverage of every recorded file.
+    pub fn files(&self) -> TreeMap<String, FileCoverage> {
+        self.files.borrow().clone()
+    }
+
+    /// Write the coverage as a lcov tracefile.
+    pub fn write_lcov<W: Writer>(&self, w: &mut W) -> IoResult<()> {
+        try!(writeln!(w, "TN:"));
+        for (filename, file) in self.files.borrow().iter() {
+            try!(writeln!(w, "SF:{}", filename));
+            for (line, hits) in file.lines.iter() {
+                try!(writeln!(w, "DA:{},{}", line, hits));
+            }
+            try!(writeln!(w, "LF:{}", file.lines_found()));
+            try!(writeln!(w, "LH:{}", file.lines_hit()));
+            try!(writeln!(w, "end_of_record"));
+        }
+        Ok(())
+    }
+
+    /// Write the coverage as a Cobertura XML report.
+    pub fn write_cobertura<W: Writer>(&self, w: &mut W) -> IoResult<()> {
+        let files = self.files.borrow();
+        let found = files.values().fold(0, |n, file| n + file.lines_found());
+        let hit = files.values().fold(0, |n, file| n + file.lines_hit());
+
+        try!(writeln!(w, "<?xml version=\"1.0\" ?>"));
+        try!(writeln!(w, "<coverage line-rate=\"{}\" branch-rate=\"0\" lines-covered=\"{}\" lines-valid=\"{}\" \
+                          version=\"1.9\" timestamp=\"{}\">",
+                      rate(hit, found), hit, found, time::get_time().sec * 1000));
+        try!(writeln!(w, "  <packages>"));
+        try!(writeln!(w, "    <package name=\"lua\" line-rate=\"{}\" branch-rate=\"0\" complexity=\"0\">",
+                      rate(hit, found)));
+        try!(writeln!(w, "      <classes>"));
+        for (filename, file) in files.iter() {
+            let filename = escape_xml(filename.as_slice());
+            try!(writeln!(w, "        <class name=\"{}\" filename=\"{}\" line-rate=\"{}\" branch-rate=\"0\" \
+                              complexity=\"0\">",
+                          filename, filename, rate(file.lines_hit(), file.lines_found())));
+            try!(writeln!(w, "          <methods/>"));
+            try!(writeln!(w, "          <lines>"));
+            for (line, hits) in file.lines.iter() {
+                try!(writeln!(w, "            <line number=\"{}\" hits=\"{}\" branch=\"false\"/>", line, hits));
+            }
+            try!(writeln!(w, "          </lines>"));
+            try!(writeln!(w, "        </class>"));
+        }
+        try!(writeln!(w, "      </classes>"));
+        try!(writeln!(w, "    </package>"));
+        try!(writeln!(w, "  </packages>"));
+        try!(writeln!(w, "</coverage>"));
+        Ok(())
+    }
+}
+
+/// Record the active lines of a prototype and its nested prototypes.
+fn add_prototype(file: &mut FileCoverage, p: &Prototype) {
+    for &line in p.line_info.iter() {
+        file.add_line(line);
+    }
+    for child in p.prototypes.iter() {
+        add_prototype(file, child);
+    }
+}
+
+fn rate(hit: uint, found: uint) -> f64 {
+    match found {
+        0 => 1.0,
+        _ => hit as f64 / found as f64,
+    }
+}
+
+fn escape_xml(s: &str) -> String {
+    let mut escaped = String::new();
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push_char(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use lua::Lua;
+
+    use super::Coverage;
+
+    #[test]
+    fn test_uncalled_function()
+    {
+        let coverage = Coverage::new();
+        {
+            let lua = Lua::new();
+            lua.set_coverage(&coverage);
+            let source = "local function unused()\n\
+                              return 1\n\
+                          end\n\
+                          local function used() return 2 end local function other()\n\
+                              return 3\n\
+                          end\n\
+                          used() other()";
+            assert_eq!(lua.exec_buffer(source.as_bytes(), "@test.lua"), ::status::LuaOk);
+            lua.remove_hook();
+        }
+
+        let files = coverage.files();
+        let file = files.find(&"test.lua".to_string()).unwrap();
+        // The body of `unused` is a missed line.
+        assert_eq!(file.lines.find(&2), Some(&0));
+        assert!(file.lines.find(&4).map_or(false, |&hits| hits > 0));
+        assert!(file.lines_hit() < file.lines_found());
+
+        // The main chunk, then `used` and `other`, both starting on line 4.
+        assert_eq!(coverage.functions.borrow().len(), 3);
+    }
+
+    #[test]
+    fn test_functions_on_same_lines()
+    {
+        let coverage = Coverage::new();
+        {
+            let lua = Lua::new();
+            let source = "a, b = function() return 1 end, function() return 2 end";
+            assert_eq!(lua.exec_buffer(source.as_bytes(), "@test.lua"), ::status::LuaOk);
+
+            // Called after the chunk ran, so recorded one by one.
+            lua.set_coverage(&coverage);
+            assert_eq!(lua.exec_str("a() b()"), ::status::LuaOk);
+            lua.remove_hook();
+        }
+
+        assert_eq!(coverage.functions.borrow().len(), 2);
+        let files = coverage.files();
+        assert_eq!(files.find(&"test.lua".to_string()).unwrap().lines.find(&1), Some(&2));
+    }
+}
@@ -0,0 +1,263 @@
+use std::mem;
+use std::str::raw;
+
+use libc::c_int;
+
+use ffi;
+use from_c_str;
+use state::State;
+
+/// Event which triggered a debug hook.
+#[deriving(Show, Eq)]
+pub enum DebugEvent {
+    HookCall,
+    HookReturn,
+    /// The interpreter is about to start the execution of a new line.
+    HookLine(int),
+    HookCount,
+    HookTailCall,
+    HookUnknown(int),
+}
+
+impl DebugEvent {
+    /// Translate Lua hook event code into an enum.
+    pub fn from_lua(event: c_int, line: c_int) -> DebugEvent {
+        match event {
+            ffi::LUA_HOOKCALL => HookCall,
+            ffi::LUA_HOOKRET => HookReturn,
+            ffi::LUA_HOOKLINE => HookLine(line as int),
+            ffi::LUA_HOOKCOUNT => HookCount,
+            ffi::LUA_HOOKTAILCALL => HookTailCall,
+            event => HookUnknown(event as int),
+        }
+    }
+}
+
+/// Set of events a debug hook is called for.
+#[deriving(Clone, Eq)]
+pub struct HookMask {
+    bits: c_int,
+}
+
+/// Called when the interpreter calls a function.
+pub static MASK_CALL: HookMask = HookMask { bits: ffi::LUA_MASKCALL };
+/// Called when the interpreter returns from a function.
+pub static MASK_RET: HookMask = HookMask { bits: ffi::LUA_MASKRET };
+/// Called when the interpreter is about to start the execution of a new line.
+pub static MASK_LINE: HookMask = HookMask { bits: ffi::LUA_MASKLINE };
+/// Called after the interpreter executes every `count` instructions.
+pub static MASK_COUNT: HookMask = HookMask { bits: ffi::LUA_MASKCOUNT };
+
+impl HookMask {
+    /// An empty mask, which disables the hook.
+    pub fn empty() -> HookMask {
+        HookMask { bits: 0 }
+    }
+
+    pub fn from_lua(bits: c_int) -> HookMask {
+        HookMask { bits: bits }
+    }
+
+    pub fn to_lua(&self) -> c_int {
+        self.bits
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits == 0
+    }
+
+    pub fn contains(&self, other: HookMask) -> bool {
+        (self.bits & other.bits) == other.bits
+    }
+}
+
+impl BitOr<HookMask, HookMask> for HookMask {
+    fn bitor(&self, rhs: &HookMask) -> HookMask {
+        HookMask { bits: self.bits | rhs.bits }
+    }
+}
+
+/**
+ *  Wrapper around a Lua activation record (`lua_Debug`).
+ *
+ *  Only the event and current line are filled when the record is given to a hook,
+ *  other fields must be requested through `get_info`.
+ */
+pub struct ActivationRecord {
+    raw: ffi::lua_Debug,
+}
+
+impl ActivationRecord {
+    /// Create a new empty activation record.
+    pub fn new() -> ActivationRecord {
+        ActivationRecord {
+            raw: unsafe { mem::zeroed() },
+        }
+    }
+
+    /// Copy an activation record given by Lua.
+    pub unsafe fn from_ffi(raw: *ffi::lua_Debug) -> ActivationRecord {
+        ActivationRecord {
+            raw: *raw,
+        }
+    }
+
+    pub fn as_ptr(&self) -> *ffi::lua_Debug {
+        &self.raw as *ffi::lua_Debug
+    }
+
+    /// Get the event which triggered the hook.
+    pub fn event(&self) -> DebugEvent {
+        DebugEvent::from_lua(self.raw.event, self.raw.currentline)
+    }
+
+    /// Get the current line of the function, or -1 if not available.
+    pub fn current_line(&self) -> int {
+        self.raw.currentline as int
+    }
+
+    /// Get the chunk source of the function, filled by the "S" option of `get_info`.
+    pub fn source(&self) -> Option<String> {
+        from_c_str(self.raw.source)
+    }
+
+    /// Get the line where the function definition starts, filled by the "S" option of `get_info`.
+    pub fn line_defined(&self) -> int {
+        self.raw.linedefined as int
+    }
+
+    /// Get the line where the function definition ends, filled by the "S" option of `get_info`.
+    pub fn last_line_defined(&self) -> int {
+        self.raw.lastlinedefined as int
+    }
+
+    /// Get what the function is ("Lua", "C" or "main"), filled by the "S" option of `get_info`.
+    pub fn what(&self) -> Option<String> {
+        from_c_str(self.raw.what)
+    }
+
+    /**
+     *  Fill the activation record fields selected by `what` (see `lua_getinfo`).
+     *
+     *  Return false if `what` is invalid.
+     */
+    pub fn get_info(&mut self, state: &State, what: &str) -> bool {
+        state.get_info(what, self)
+    }
+
+    /// Get all the information about the function of this record.
+    pub fn info(&mut self, state: &State) -> DebugInfo {
+        self.get_info(state, "nSltu");
+
+        DebugInfo {
+            source: from_c_str(self.raw.source).unwrap_or(String::new()),
+            short_src: unsafe { raw::from_c_str(self.raw.short_src.as_ptr()) },
+            what: from_c_str(self.raw.what).unwrap_or(String::new()),
+            name: from_c_str(self.raw.name),
+            namewhat: from_c_str(self.raw.namewhat).unwrap_or(String::new()),
+            current_line: self.raw.currentline as int,
+            line_defined: self.raw.linedefined as int,
+            last_line_defined: self.raw.lastlinedefined as int,
+            nups: self.raw.nups as uint,
+            nparams: self.raw.nparams as uint,
+            is_vararg: self.raw.isvararg != 0,
+            is_tail_call: self.raw.istailcall != 0,
+        }
+    }
+}
+
+/// Information about a function, as given by `lua_getinfo`.
+#[deriving(Show, Clone)]
+pub struct DebugInfo {
+    /// Source of the chunk which created the function.
+    pub source: String,
+    /// "Printable" version of `source`, used in error messages.
+    pub short_src: String,
+    /// "Lua" for a Lua function, "C" for a C function, "main" for the main part of a chunk.
+    pub what: String,
+    /// Reasonable name for the function, if any.
+    pub name: Option<String>,
+    /// "global", "local", "method", "field", "upvalue" or "" according to how the function was called.
+    pub namewhat: String,
+    /// Current line of the function, or -1 if not available.
+    pub current_line: int,
+    pub line_defined: int,
+    pub last_line_defined: int,
+    /// Number of upvalues of the function.
+    pub nups: uint,
+    /// Number of fixed parameters of the function.
+    pub nparams: uint,
+    pub is_vararg: bool,
+    /// Whether this function invocation was called by a tail call.
+    pub is_tail_call: bool,
+}
+
+/// Iterator over the stack frames of a Lua state, starting from the current running function.
+pub struct Frames<'a, 'b> {
+    state: &'b State<'a>,
+    level: int,
+}
+
+impl<'a, 'b> Frames<'a, 'b> {
+    pub fn new(state: &'b State<'a>) -> Frames<'a, 'b> {
+        Frames {
+            state: state,
+            level: 0,
+        }
+    }
+}
+
+impl<'a, 'b> Iterator<ActivationRecord> for Frames<'a, 'b> {
+    fn next(&mut self) -> Option<ActivationRecord> {
+        let ar = self.state.get_stack(self.level);
+        if ar.is_some() {
+            self.level += 1;
+        }
+        ar
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use lua::Lua;
+
+    fn where_am_i(lua: &Lua) -> int {
+        let frames = lua.backtrace();
+        // Level 0 is this function, level 1 its caller.
+        let caller = &frames.as_slice()[1];
+        lua.push(frames.len());
+        lua.push(frames.as_slice()[0].what.clone());
+        lua.push(caller.name.clone());
+        lua.push(caller.current_line);
+        lua.push(caller.line_defined);
+        5
+    }
+
+    #[test]
+    fn test_backtrace()
+    {
+        let lua = Lua::new();
+        lua.state.push_function(where_am_i);
+        lua.state.set_global("where_am_i");
+        assert_eq!(lua.exec_str("local function inner()\n\
+                                     local depth, what, name, line, defined = where_am_i()\n\
+                                     return depth, what, name, line, defined\n\
+                                 end\n\
+                                 depth, what, name, line, defined = inner()"), ::status::LuaOk);
+
+        assert_eq!(lua.get_global("depth"), Some(3u));
+        assert_eq!(lua.get_global("what"), Some("C".to_string()));
+        assert_eq!(lua.get_global("name"), Some("inner".to_string()));
+        assert_eq!(lua.get_global("line"), Some(2));
+        assert_eq!(lua.get_global("defined"), Some(1));
+    }
+
+    #[test]
+    fn test_stack_frames()
+    {
+        let lua = Lua::new();
+        // Nothing is running.
+        assert!(lua.stack_frames().next().is_none());
+        assert!(lua.state.get_stack(0).is_none());
+    }
+}
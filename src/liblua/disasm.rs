@@ -0,0 +1,263 @@
+use std::io::{IoResult, Writer};
+
+use bytecode::{Chunk, Prototype, Constant, ConstNil, ConstBool, ConstNumber, ConstString};
+use opcodes::*;
+
+/**
+ *  Write the listing of every function of a chunk, like `luac -l`.
+ *
+ *  When `full` is true, the constants, locals and upvalues of every function are listed too,
+ *  like `luac -l -l`.
+ */
+pub fn write_listing<W: Writer>(w: &mut W, chunk: &Chunk, full: bool) -> IoResult<()> {
+    write_function(w, &chunk.main, full)
+}
+
+/// Write the listing of a function and its nested functions.
+pub fn write_function<W: Writer>(w: &mut W, p: &Prototype, full: bool) -> IoResult<()> {
+    try!(write_header(w, p));
+
+    let mut pc = 0;
+    while pc < p.code.len() {
+        try!(writeln!(w, "\t{}", format_instruction(p, pc)));
+
+        // `SETLIST` takes its count from the next instruction when C is 0.
+        let i = Instruction(p.code[pc]);
+        if i.opcode() == Some(OpSetList) && i.c() == 0 {
+            pc += 1;
+        }
+        pc += 1;
+    }
+
+    if full {
+        try!(write_debug(w, p));
+    }
+
+    for nested in p.prototypes.iter() {
+        try!(write_function(w, nested, full));
+    }
+    Ok(())
+}
+
+/**
+ *  Format the instruction at `pc`, with its position, line, opcode, arguments and a comment
+ *  describing the constants, upvalues or jump target it refers to.
+ */
+pub fn format_instruction(p: &Prototype, pc: uint) -> String {
+    let i = Instruction(p.code[pc]);
+    let line = match p.line_info.as_slice().get(pc) {
+        Some(&line) if line > 0 => format!("[{}]", line),
+        _ => "[-]".to_string(),
+    };
+    let op = match i.opcode() {
+        Some(op) => op,
+        None => return format!("{}\t{}\t?", pc + 1, line),
+    };
+
+    let mut s = format!("{}\t{}\t{:<9}\t", pc + 1, line, op.name());
+    let (a, b, c) = (i.a(), i.b(), i.c());
+    match op.mode() {
+        ModeABC => {
+            s.push_str(format!("{}", a).as_slice());
+            if op.b_mode() != ArgN {
+                s.push_str(format!(" {}", rk(b)).as_slice());
+            }
+            if op.c_mode() != ArgN {
+                s.push_str(format!(" {}", rk(c)).as_slice());
+            }
+        }
+        ModeABx => {
+            s.push_str(format!("{}", a).as_slice());
+            match op.b_mode() {
+                ArgK => s.push_str(format!(" {}", -1 - i.bx() as int).as_slice()),
+                ArgU => s.push_str(format!(" {}", i.bx()).as_slice()),
+                _ => {}
+            }
+        }
+        ModeAsBx => s.push_str(format!("{} {}", a, i.sbx()).as_slice()),
+        ModeAx => s.push_str(format!("{}", -1 - i.ax() as int).as_slice()),
+    }
+
+    let comment = match op {
+        OpLoadK => Some(constant(p, i.bx())),
+        OpGetUpval | OpSetUpval => Some(upvalue_name(p, b)),
+        OpGetTabUp => Some(format!("{}{}", upvalue_name(p, b), rk_constants(p, [c]))),
+        OpSetTabUp => Some(format!("{}{}", upvalue_name(p, a), rk_constants(p, [b, c]))),
+        OpGetTable | OpSelf if is_constant(c) => Some(constant(p, constant_index(c))),
+        OpSetTable | OpAdd | OpSub | OpMul | OpDiv | OpPow | OpEq | OpLt | OpLe
+                if is_constant(b) || is_constant(c) => {
+            let rk_or_dash = |x: uint| match is_constant(x) {
+                true => constant(p, constant_index(x)),
+                false => "-".to_string(),
+            };
+            Some(format!("{} {}", rk_or_dash(b), rk_or_dash(c)))
+        }
+        OpJmp | OpForLoop | OpForPrep | OpTForLoop => Some(format!("to {}", i.sbx() + pc as int + 2)),
+        OpClosure => Some(match p.prototypes.as_slice().get(i.bx()) {
+            Some(nested) => format!("function <{}:{},{}>", source_name(nested), nested.line_defined, nested.last_line_defined),
+            None => "?".to_string(),
+        }),
+        OpSetList => Some(match c {
+            0 => p.code.as_slice().get(pc + 1).map(|n| n.to_string()).unwrap_or("?".to_string()),
+            c => c.to_string(),
+        }),
+        OpExtraArg => Some(constant(p, i.ax())),
+        _ => None,
+    };
+
+    match comment {
+        Some(comment) => format!("{}\t; {}", s, comment),
+        None => s,
+    }
+}
+
+/// Format a constant as in Lua source.
+pub fn format_constant(k: &Constant) -> String {
+    match *k {
+        ConstNil => "nil".to_string(),
+        ConstBool(b) => b.to_string(),
+        ConstNumber(n) => format_number(n),
+        ConstString(ref s) => quote(s.as_slice()),
+    }
+}
+
+fn write_header<W: Writer>(w: &mut W, p: &Prototype) -> IoResult<()> {
+    let kind = match p.line_defined {
+        0 => "main",
+        _ => "function",
+    };
+    try!(writeln!(w, "\n{} <{}:{},{}> ({} instruction{})", kind, source_name(p),
+                  p.line_defined, p.last_line_defined, p.code.len(), plural(p.code.len())));
+    try!(write!(w, "{}{} param{}, {} slot{}, {} upvalue{}, ",
+                p.num_params, if p.is_vararg != 0 { "+" } else { "" }, plural(p.num_params),
+                p.max_stack_size, plural(p.max_stack_size), p.upvalues.len(), plural(p.upvalues.len())));
+    writeln!(w, "{} local{}, {} constant{}, {} function{}",
+             p.local_vars.len(), plural(p.local_vars.len()), p.constants.len(), plural(p.constants.len()),
+             p.prototypes.len(), plural(p.prototypes.len()))
+}
+
+fn write_debug<W: Writer>(w: &mut W, p: &Prototype) -> IoResult<()> {
+    try!(writeln!(w, "constants ({}):", p.constants.len()));
+    for (i, k) in p.constants.iter().enumerate() {
+        try!(writeln!(w, "\t{}\t{}", i + 1, format_constant(k)));
+    }
+
+    try!(writeln!(w, "locals ({}):", p.local_vars.len()));
+    for (i, var) in p.local_vars.iter().enumerate() {
+        try!(writeln!(w, "\t{}\t{}\t{}\t{}", i, var.name, var.start_pc + 1, var.end_pc + 1));
+    }
+
+    try!(writeln!(w, "upvalues ({}):", p.upvalues.len()));
+    for (i, upvalue) in p.upvalues.iter().enumerate() {
+        try!(writeln!(w, "\t{}\t{}\t{}\t{}", i, upvalue_name(p, i), upvalue.in_stack as uint, upvalue.index));
+    }
+    Ok(())
+}
+
+/// Get the source of a function, as displayed in listings.
+fn source_name(p: &Prototype) -> String {
+    match p.source {
+        Some(ref source) if source.as_slice().starts_with("@") || source.as_slice().starts_with("=") => {
+            source.as_slice().slice_from(1).to_string()
+        }
+        Some(ref source) if source.as_slice().starts_with("\x1b") => "(bstring)".to_string(),
+        Some(_) => "(string)".to_string(),
+        None => "?".to_string(),
+    }
+}
+
+/// Decode a RK argument, constants being shown as negative numbers.
+fn rk(x: uint) -> int {
+    match is_constant(x) {
+        true => -1 - constant_index(x) as int,
+        false => x as int,
+    }
+}
+
+/// Format the constants referred to by RK arguments, each preceded by a space.
+fn rk_constants(p: &Prototype, args: &[uint]) -> String {
+    let mut s = String::new();
+    for &x in args.iter() {
+        if is_constant(x) {
+            s.push_str(format!(" {}", constant(p, constant_index(x))).as_slice());
+        }
+    }
+    s
+}
+
+fn constant(p: &Prototype, idx: uint) -> String {
+    match p.constants.as_slice().get(idx) {
+        Some(k) => format_constant(k),
+        None => "?".to_string(),
+    }
+}
+
+fn upvalue_name(p: &Prototype, idx: uint) -> String {
+    match p.upvalue_names.as_slice().get(idx) {
+        Some(name) => name.clone(),
+        None => "-".to_string(),
+    }
+}
+
+/// Format a number like `LUA_NUMBER_FMT` (`%.14g`) does for usual values.
+fn format_number(n: f64) -> String {
+    if n.is_nan() {
+        "nan".to_string()
+    } else if n.is_infinite() {
+        match n > 0.0 {
+            true => "inf".to_string(),
+            false => "-inf".to_string(),
+        }
+    } else if n == n.floor() && n.abs() < 1e15 {
+        format!("{}", n as i64)
+    } else {
+        format!("{}", n)
+    }
+}
+
+/// Quote a string, escaping special and non-printable characters.
+fn quote(s: &[u8]) -> String {
+    let mut quoted = String::from_str("\"");
+    for &c in s.iter() {
+        match c as char {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\x07' => quoted.push_str("\\a"),
+            '\x08' => quoted.push_str("\\b"),
+            '\x0c' => quoted.push_str("\\f"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            '\x0b' => quoted.push_str("\\v"),
+            ' '..'~' => quoted.push_char(c as char),
+            _ => quoted.push_str(format!("\\{:03}", c).as_slice()),
+        }
+    }
+    quoted.push_char('"');
+    quoted
+}
+
+fn plural(n: uint) -> &'static str {
+    match n {
+        1 => "",
+        _ => "s",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use lua::Lua;
+    use bytecode::Chunk;
+    use status::LuaOk;
+
+    use super::format_instruction;
+
+    #[test]
+    fn test_disasm()
+    {
+        let lua = Lua::new();
+        assert_eq!(lua.state.load_str("x = 1"), LuaOk);
+        let chunk = Chunk::parse(lua.dump(false).unwrap().as_slice()).unwrap();
+        assert_eq!(format_instruction(&chunk.main, 0).as_slice(), "1\t[1]\tSETTABUP \t0 -1 -2\t; _ENV \"x\" 1");
+    }
+}
@@ -0,0 +1,40 @@
+use std::io::{IoError, Writer};
+use std::mem::transmute;
+use std::slice::raw;
+
+use libc::{c_int, c_void, size_t};
+
+use ffi;
+
+/// State of a Rust writer used by `lua_dump`.
+pub struct WriterData<'a> {
+    writer: &'a mut Writer,
+    /// First write error, if any.
+    pub error: Option<IoError>,
+}
+
+impl<'a> WriterData<'a> {
+    pub fn new(writer: &'a mut Writer) -> WriterData<'a> {
+        WriterData {
+            writer: writer,
+            error: None,
+        }
+    }
+}
+
+/// `lua_Writer` callback writing to a `WriterData`.
+pub extern "C" fn _lua_writer(_: *ffi::lua_State, p: *c_void, sz: size_t, ud: *c_void) -> c_int {
+    let data: &mut WriterData = unsafe { transmute(ud) };
+
+    let result = unsafe {
+        raw::buf_as_slice(p as *u8, sz as uint, |buf| data.writer.write(buf))
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            data.error = Some(e);
+            1
+        }
+    }
+}
@@ -9,7 +9,7 @@ pub struct lua_State;
 pub struct lua_Debug {
     pub event: c_int,
     pub name: *c_char,
-    pub namewhar: *c_char,
+    pub namewhat: *c_char,
     pub what: *c_char,
     pub source: *c_char,
     pub currentline: c_int,
@@ -21,13 +21,14 @@ pub struct lua_Debug {
     pub istailcall: c_char,
     pub short_src: [c_char, ..LUA_IDSIZE],
 
+    // Private part
     i_ci: *c_void,
 }
 
 pub type lua_CFunction = extern "C" fn(L: *lua_State) -> c_int;
 
-pub type lua_Reader = extern "C" fn(L: *lua_State, ud: *c_void, sz: size_t) -> *c_char;
-pub type lua_Writer = extern "C" fn(L: *lua_State, p: *c_void, ze: size_t, ud: *c_void);
+pub type lua_Reader = extern "C" fn(L: *lua_State, ud: *c_void, sz: *size_t) -> *c_char;
+pub type lua_Writer = extern "C" fn(L: *lua_State, p: *c_void, sz: size_t, ud: *c_void) -> c_int;
 
 pub type lua_Alloc = extern "C" fn(ud: *c_void, ptr: *c_void, osize: size_t, nsize: size_t);
 
@@ -35,12 +36,17 @@ pub type lua_Integer = ptrdiff_t;
 pub type lua_Number = c_double;
 pub type lua_Unsigned = c_ulong;
 
-pub type lua_Hook = extern fn(L: *lua_State, ar: *lua_Debug);
+pub type lua_Hook = extern "C" fn(L: *lua_State, ar: *lua_Debug);
 
 // Constants
+pub static LUA_VERSION: &'static str = "Lua 5.2";
+pub static LUA_RELEASE: &'static str = "Lua 5.2.3";
+pub static LUA_COPYRIGHT: &'static str = "Lua 5.2.3  Copyright (C) 1994-2013 Lua.org, PUC-Rio";
+pub static LUA_AUTHORS: &'static str = "R. Ierusalimschy, L. H. de Figueiredo, W. Celes";
+
 pub static LUAI_MAXSTACK: c_int = 1000000;
 pub static LUAI_FIRSTPSEUDOIDX: c_int = -LUAI_MAXSTACK - 1000;
-pub static LUA_IDSIZE: c_int = 60;
+pub static LUA_IDSIZE: uint = 60;
 pub static LUA_MULTRET: c_int = -1;
 
 // - Pseudo-indices
@@ -105,6 +111,17 @@ pub static LUA_GCISRUNNING: c_int = 9;
 pub static LUA_GCGEN: c_int = 10;
 pub static LUA_GCINC: c_int = 11;
 
+// - Standard library names
+pub static LUA_COLIBNAME: &'static str = "coroutine";
+pub static LUA_TABLIBNAME: &'static str = "table";
+pub static LUA_IOLIBNAME: &'static str = "io";
+pub static LUA_OSLIBNAME: &'static str = "os";
+pub static LUA_STRLIBNAME: &'static str = "string";
+pub static LUA_BITLIBNAME: &'static str = "bit32";
+pub static LUA_MATHLIBNAME: &'static str = "math";
+pub static LUA_DBLIBNAME: &'static str = "debug";
+pub static LUA_LOADLIBNAME: &'static str = "package";
+
 // - Event codes
 pub static LUA_HOOKCALL: c_int = 0;
 pub static LUA_HOOKRET: c_int = 1;
@@ -118,7 +135,12 @@ pub static LUA_MASKRET: c_int = 1 << LUA_HOOKRET;
 pub static LUA_MASKLINE: c_int = 1 << LUA_HOOKLINE;
 pub static LUA_MASKCOUNT: c_int = 1 << LUA_HOOKCOUNT;
 
+// Native Lua modules (built with `--cfg lua_module`) use the Lua symbols of the interpreter
+// loading them, instead of embedding their own copy of Lua.
+#[cfg(not(lua_module))]
 #[link(name = "lua")]
+extern {}
+
 extern {
     // State manipulation
     pub fn lua_newstate(f: lua_Alloc, ud: *c_void) -> *lua_State;
@@ -247,8 +269,26 @@ extern {
     // Auxiliary API
     pub fn luaL_newstate() -> *lua_State;
     pub fn luaL_openlibs(L: *lua_State);
+
+    // Standard libraries
+    pub fn luaopen_base(L: *lua_State) -> c_int;
+    pub fn luaopen_coroutine(L: *lua_State) -> c_int;
+    pub fn luaopen_table(L: *lua_State) -> c_int;
+    pub fn luaopen_io(L: *lua_State) -> c_int;
+    pub fn luaopen_os(L: *lua_State) -> c_int;
+    pub fn luaopen_string(L: *lua_State) -> c_int;
+    pub fn luaopen_bit32(L: *lua_State) -> c_int;
+    pub fn luaopen_math(L: *lua_State) -> c_int;
+    pub fn luaopen_debug(L: *lua_State) -> c_int;
+    pub fn luaopen_package(L: *lua_State) -> c_int;
     pub fn luaL_loadfilex(L: *lua_State, filename: *c_char, mode: *c_char) -> c_int;
+    pub fn luaL_loadbufferx(L: *lua_State, buff: *c_char, sz: size_t, name: *c_char, mode: *c_char) -> c_int;
     pub fn luaL_loadstring(L: *lua_State, s: *c_char) -> c_int;
+    pub fn luaL_tolstring(L: *lua_State, idx: c_int, len: *size_t) -> *c_char;
+    pub fn luaL_callmeta(L: *lua_State, obj: c_int, e: *c_char) -> c_int;
+    pub fn luaL_traceback(L: *lua_State, L1: *lua_State, msg: *c_char, level: c_int);
+    pub fn luaL_where(L: *lua_State, lvl: c_int);
+    pub fn luaL_requiref(L: *lua_State, modname: *c_char, openf: lua_CFunction, glb: c_int);
     pub fn luaL_ref(L: *lua_State, t: c_int) -> c_int;
     pub fn luaL_unref(L: *lua_State, t: c_int, r: c_int);
 }
@@ -341,3 +381,7 @@ pub unsafe fn lua_pushglobaltable(L: *lua_State) {
 pub unsafe fn luaL_loadfile(L: *lua_State, filename: *c_char) -> c_int {
     luaL_loadfilex(L, filename, transmute(null::<c_void>()))
 }
+
+pub unsafe fn luaL_loadbuffer(L: *lua_State, buff: *c_char, sz: size_t, name: *c_char) -> c_int {
+    luaL_loadbufferx(L, buff, sz, name, transmute(null::<c_void>()))
+}
@@ -0,0 +1,102 @@
+use ffi;
+use lua::Lua;
+use state::State;
+use debug::{MASK_CALL, MASK_RET, MASK_COUNT};
+
+/// Error raised in interrupted scripts.
+pub static INTERRUPTED: &'static str = "interrupted!";
+
+/**
+ *  Handle interrupting the script running in a Lua state, e.g. from a signal handler or another task.
+ *
+ *  Like `lua.c` on SIGINT, the interruption sets a hook raising an "interrupted!" error at the
+ *  next call, return or instruction. The hook set with `Lua::set_hook`, if any, is then restored.
+ *  If no script is running, the next one is interrupted.
+ *
+ *  The handle must not outlive its Lua state.
+ */
+pub struct InterruptHandle {
+    raw: *ffi::lua_State,
+}
+
+impl InterruptHandle {
+    /// Create a handle for an existing Lua state.
+    pub unsafe fn from_ffi(raw: *ffi::lua_State) -> InterruptHandle {
+        InterruptHandle {
+            raw: raw,
+        }
+    }
+
+    /// Interrupt the running script.
+    pub fn interrupt(&self) {
+        let mask = MASK_CALL | MASK_RET | MASK_COUNT;
+        // `lua_sethook` is safe to call asynchronously.
+        unsafe {
+            ffi::lua_sethook(self.raw, _lua_interrupt, mask.to_lua(), 1);
+        }
+    }
+}
+
+extern "C" fn _lua_interrupt(raw: *ffi::lua_State, _: *ffi::lua_Debug) {
+    {
+        let lua = Lua::from_state(unsafe {
+            State::from_ffi(raw)
+        });
+        lua.restore_hook();
+    }
+
+    unsafe {
+        ffi::luaL_where(raw, 1);
+    }
+    INTERRUPTED.with_c_str(|msg| unsafe {
+        ffi::lua_pushstring(raw, msg);
+    });
+    unsafe {
+        ffi::lua_concat(raw, 2);
+        ffi::lua_error(raw);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+
+    use lua::Lua;
+    use debug::MASK_LINE;
+    use status::{LuaOk, LuaErr, RuntimeError};
+
+    use super::INTERRUPTED;
+
+    #[test]
+    fn test_interrupt()
+    {
+        let lua = Lua::new();
+        unsafe { lua.interrupt_handle() }.interrupt();
+        assert_eq!(lua.exec_str("while true do end"), LuaErr(RuntimeError));
+        let msg: String = lua.pop().unwrap();
+        assert!(msg.as_slice().ends_with(INTERRUPTED));
+
+        // The hook is removed once triggered.
+        assert_eq!(lua.exec_str("a = 1"), LuaOk);
+    }
+
+    #[test]
+    fn test_interrupt_restores_hook()
+    {
+        let lines = Cell::new(0u);
+        {
+            let lua = Lua::new();
+            lua.set_hook(MASK_LINE, 0, |_, _, _| {
+                lines.set(lines.get() + 1);
+                Ok(())
+            });
+            unsafe { lua.interrupt_handle() }.interrupt();
+            assert_eq!(lua.exec_str("while true do end"), LuaErr(RuntimeError));
+            lua.state.pop(1);
+
+            let before = lines.get();
+            assert_eq!(lua.exec_str("local a = 1\nlocal b = 2"), LuaOk);
+            assert_eq!(lines.get(), before + 2);
+        }
+    }
+}
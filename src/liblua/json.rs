@@ -0,0 +1,686 @@
+use std::char;
+use std::str;
+
+use ffi;
+use lua::Lua;
+use state::State;
+use serial::{Key, NumberKey, StringKey, BoolKey, Tables, CycleError, DepthError, MAX_DEPTH};
+use serial::{format_number, parse_number, array_len, table_keys};
+use types::*;
+
+/// Address of the `json.null` light userdata.
+static NULL: u8 = 0;
+
+/// Maximum number of spaces of an indentation level.
+static MAX_INDENT: uint = 100;
+
+// Address used as registry key for the metatable of decoded arrays.
+static ARRAY_KEY: u8 = 0;
+
+/**
+ *  Open the `json` module, to be registered with `Lua::register_module`:
+ *
+ *  ```ignore
+ *  lua.register_module("json", lua::json::open);
+ *  ```
+ *
+ *  - `json.encode(value [, options])` returns the JSON text of a value.
+ *    Tables whose keys are exactly `1..n` are encoded as arrays, other ones as objects.
+ *    Empty tables are objects, unless their metatable `__jsontype` field is `"array"`.
+ *    `options` may hold `indent` (a number of spaces up to 100, or a string) to pretty print,
+ *    and `sort_keys` to sort object keys.
+ *  - `json.decode(text)` returns the value of a JSON text. Arrays get a metatable whose
+ *    `__jsontype` field is `"array"`, so that empty arrays are encoded back as arrays.
+ *  - `json.null` represents JSON null in arrays and objects, where nil can't be stored.
+ *
+ *  Errors are raised, with their position in the text when decoding.
+ */
+pub fn open(lua: &Lua) -> int {
+    lua.new_lib([("encode", encode), ("decode", decode)]);
+    lua.state.push_userdata(&NULL as *u8);
+    lua.state.set_field(-2, "null");
+    1
+}
+
+fn encode(lua: &Lua) -> int {
+    let result = {
+        let mut encoder = Encoder {
+            lua: lua,
+            out: Vec::new(),
+            indent: None,
+            sort_keys: false,
+            tables: Tables::new(),
+        };
+
+        let mut options = Ok(());
+        match lua.state.get_type(2) {
+            LuaTable => {
+                lua.state.get_field(2, "indent");
+                encoder.indent = match lua.state.get_type(-1) {
+                    LuaNumber => {
+                        let n = lua.state.get_float(-1);
+                        if n >= 0.0 && n <= MAX_INDENT as f64 && n == n.floor() {
+                            Some(" ".repeat(n as uint))
+                        } else {
+                            options = Err(format!("invalid indent {}", n));
+                            None
+                        }
+                    }
+                    LuaString => Some(lua.state.get_str(-1)),
+                    _ => None,
+                };
+                lua.state.get_field(2, "sort_keys");
+                encoder.sort_keys = lua.state.get_bool(-1);
+                lua.state.pop(2);
+            }
+            _ => {}
+        }
+
+        match options.and_then(|()| encoder.value(1, 0)) {
+            Ok(()) => {
+                lua.state.push_bytes(encoder.out.as_slice());
+                Ok(())
+            }
+            Err(msg) => {
+                lua.push(format!("json: {}", msg));
+                Err(())
+            }
+        }
+    };
+
+    match result {
+        Ok(()) => 1,
+        Err(()) => lua.state.error(),
+    }
+}
+
+fn decode(lua: &Lua) -> int {
+    let top = lua.state.get_top();
+    let result = {
+        let parsed = match lua.state.get_type(1) {
+            LuaString => {
+                let text = lua.state.get_bytes(1);
+                let mut parser = Parser {
+                    lua: lua,
+                    src: text.as_slice(),
+                    pos: 0,
+                    depth: 0,
+                };
+                parser.document()
+            }
+            ty => Err(format!("string expected, got {}", ty)),
+        };
+
+        match parsed {
+            Ok(()) => Ok(()),
+            Err(msg) => {
+                lua.state.set_top(top);
+                lua.push(format!("json: {}", msg));
+                Err(())
+            }
+        }
+    };
+
+    match result {
+        Ok(()) => 1,
+        Err(()) => lua.state.error(),
+    }
+}
+
+struct Encoder<'a, 'b> {
+    lua: &'a Lua<'b>,
+    out: Vec<u8>,
+    indent: Option<String>,
+    sort_keys: bool,
+    /// Tables being encoded, to detect cycles.
+    tables: Tables,
+}
+
+impl<'a, 'b> Encoder<'a, 'b> {
+    /// Encode the value at the absolute index `idx`.
+    fn value(&mut self, idx: int, level: uint) -> Result<(), String> {
+        let lua = self.lua;
+        let state = &lua.state;
+        match state.get_type(idx) {
+            LuaNil => self.out.push_all(bytes!("null")),
+            LuaBoolean => match state.get_bool(idx) {
+                true => self.out.push_all(bytes!("true")),
+                false => self.out.push_all(bytes!("false")),
+            },
+            LuaNumber => {
+                let n = state.get_float(idx);
+                if n.is_nan() || n.is_infinite() {
+                    return Err(format!("cannot encode number {}", n));
+                }
+                self.out.push_all(format_number(n).as_bytes());
+            }
+            LuaString => {
+                let s = state.get_bytes(idx);
+                self.string(s.as_slice());
+            }
+            LuaLightUserData if state.get_userdata::<u8>(idx) == &NULL as *u8 => self.out.push_all(bytes!("null")),
+            LuaTable => return self.table(idx, level),
+            ty => return Err(format!("cannot encode {}", ty)),
+        }
+        Ok(())
+    }
+
+    fn table(&mut self, idx: int, level: uint) -> Result<(), String> {
+        let lua = self.lua;
+        let state = &lua.state;
+        match self.tables.enter(state, idx, level) {
+            Ok(()) => {}
+            Err(CycleError) => return Err("cannot encode circular reference".to_string()),
+            Err(DepthError) => return Err("cannot encode too deeply nested table".to_string()),
+        }
+        let result = self.table_content(idx, level);
+        self.tables.leave(state, idx);
+        result
+    }
+
+    fn table_content(&mut self, idx: int, level: uint) -> Result<(), String> {
+        let lua = self.lua;
+        let state = &lua.state;
+        let keys = try!(table_keys(state, idx, 0, |ty| {
+            Err(format!("cannot encode table key of type {}", ty))
+        }));
+
+        // Keys are exactly `1..n` if there are as many as the length of the sequence `1..n`,
+        // in which case there are no boolean keys.
+        let len = array_len(state, idx) as uint;
+        let is_array = match keys.len() {
+            0 => self.is_marked_array(idx),
+            n => n == len,
+        };
+
+        match is_array {
+            true => self.array(idx, len, level),
+            false => self.object(idx, keys, level),
+        }
+    }
+
+    /// Whether the metatable of the table at `idx` marks it as an array.
+    fn is_marked_array(&self, idx: int) -> bool {
+        let state = &self.lua.state;
+        if !state.get_metatable(idx) {
+            return false;
+        }
+        state.get_field(-1, "__jsontype");
+        let marked = match state.get_type(-1) {
+            LuaString => state.get_str(-1).as_slice() == "array",
+            _ => false,
+        };
+        state.pop(2);
+        marked
+    }
+
+    fn array(&mut self, idx: int, len: uint, level: uint) -> Result<(), String> {
+        self.out.push('[' as u8);
+        for i in range(0, len) {
+            if i > 0 {
+                self.out.push(',' as u8);
+            }
+            self.newline(level + 1);
+
+            self.lua.state.raw_get_index(idx, (i + 1) as int);
+            let top = self.lua.state.get_top();
+            let result = self.value(top, level + 1);
+            self.lua.state.pop(1);
+            try!(result);
+        }
+        if len > 0 {
+            self.newline(level);
+        }
+        self.out.push(']' as u8);
+        Ok(())
+    }
+
+    fn object(&mut self, idx: int, keys: Vec<Key>, level: uint) -> Result<(), String> {
+        let mut entries = Vec::new();
+        for key in keys.move_iter() {
+            let name = match key {
+                NumberKey(n) => Vec::from_slice(format_number(n).as_bytes()),
+                StringKey(ref s) => s.clone(),
+                BoolKey(_) => return Err("cannot encode table key of type boolean".to_string()),
+            };
+            entries.push((name, key));
+        }
+        if self.sort_keys {
+            entries.sort_by(|&(ref a, _), &(ref b, _)| a.cmp(b));
+        }
+
+        self.out.push('{' as u8);
+        for (i, &(ref name, ref key)) in entries.iter().enumerate() {
+            if i > 0 {
+                self.out.push(',' as u8);
+            }
+            self.newline(level + 1);
+            self.string(name.as_slice());
+            self.out.push(':' as u8);
+            if self.indent.is_some() {
+                self.out.push(' ' as u8);
+            }
+
+            key.push(&self.lua.state);
+            self.lua.state.raw_get(idx);
+            let top = self.lua.state.get_top();
+            let result = self.value(top, level + 1);
+            self.lua.state.pop(1);
+            try!(result);
+        }
+        if !entries.is_empty() {
+            self.newline(level);
+        }
+        self.out.push('}' as u8);
+        Ok(())
+    }
+
+    /// Start a new line indented for `level`, when pretty printing.
+    fn newline(&mut self, level: uint) {
+        match self.indent {
+            Some(ref indent) => {
+                self.out.push('\n' as u8);
+                for _ in range(0, level) {
+                    self.out.push_all(indent.as_bytes());
+                }
+            }
+            None => {}
+        }
+    }
+
+    fn string(&mut self, s: &[u8]) {
+        self.out.push('"' as u8);
+        for &c in s.iter() {
+            match c as char {
+                '"' => self.out.push_all(bytes!("\\\"")),
+                '\\' => self.out.push_all(bytes!("\\\\")),
+                '\n' => self.out.push_all(bytes!("\\n")),
+                '\r' => self.out.push_all(bytes!("\\r")),
+                '\t' => self.out.push_all(bytes!("\\t")),
+                '\x08' => self.out.push_all(bytes!("\\b")),
+                '\x0c' => self.out.push_all(bytes!("\\f")),
+                '\x00'..'\x1f' | '\x7f' => self.out.push_all(format!("\\u{:04x}", c).as_bytes()),
+                _ => self.out.push(c),
+            }
+        }
+        self.out.push('"' as u8);
+    }
+}
+
+struct Parser<'a, 'b> {
+    lua: &'a Lua<'b>,
+    src: &'a [u8],
+    pos: uint,
+    depth: uint,
+}
+
+impl<'a, 'b> Parser<'a, 'b> {
+    /// Parse a whole JSON text, pushing its value.
+    fn document(&mut self) -> Result<(), String> {
+        try!(self.value());
+        self.skip_whitespace();
+        match self.peek() {
+            Some(_) => self.unexpected(),
+            None => Ok(()),
+        }
+    }
+
+    fn value(&mut self) -> Result<(), String> {
+        if self.depth >= MAX_DEPTH || !self.lua.state.check_stack(3) {
+            return self.error("too deeply nested");
+        }
+
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{') => self.object(),
+            Some('[') => self.array(),
+            Some('"') => {
+                let s = try!(self.string());
+                self.lua.state.push_bytes(s.as_slice());
+                Ok(())
+            }
+            Some('t') => self.literal("true", |state| state.push_bool(true)),
+            Some('f') => self.literal("false", |state| state.push_bool(false)),
+            Some('n') => self.literal("null", |state| state.push_userdata(&NULL as *u8)),
+            Some('-') | Some('0'..'9') => self.number(),
+            _ => self.unexpected(),
+        }
+    }
+
+    fn object(&mut self) -> Result<(), String> {
+        self.pos += 1;
+        self.depth += 1;
+        self.lua.state.new_table();
+
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            self.depth -= 1;
+            return Ok(());
+        }
+
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some('"') {
+                return self.unexpected();
+            }
+            let key = try!(self.string());
+            self.lua.state.push_bytes(key.as_slice());
+
+            self.skip_whitespace();
+            try!(self.expect(':'));
+            try!(self.value());
+            self.lua.state.raw_set(-3);
+
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some('}') => {
+                    self.pos += 1;
+                    break;
+                }
+                _ => return self.unexpected(),
+            }
+        }
+        self.depth -= 1;
+        Ok(())
+    }
+
+    fn array(&mut self) -> Result<(), String> {
+        self.pos += 1;
+        self.depth += 1;
+        self.lua.state.new_table();
+        self.set_array_metatable();
+
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.pos += 1;
+            self.depth -= 1;
+            return Ok(());
+        }
+
+        let mut i = 1;
+        loop {
+            try!(self.value());
+            self.lua.state.raw_set_index(-2, i);
+            i += 1;
+
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(']') => {
+                    self.pos += 1;
+                    break;
+                }
+                _ => return self.unexpected(),
+            }
+        }
+        self.depth -= 1;
+        Ok(())
+    }
+
+    /// Set the metatable marking arrays to the table at the top of the stack.
+    fn set_array_metatable(&mut self) {
+        let state = &self.lua.state;
+        state.raw_get_ptr(ffi::LUA_REGISTRYINDEX as int, &ARRAY_KEY as *u8);
+        match state.get_type(-1) {
+            LuaTable => {}
+            _ => {
+                state.pop(1);
+                state.new_table();
+                state.push_str("array");
+                state.set_field(-2, "__jsontype");
+                state.push_value(-1);
+                state.raw_set_ptr(ffi::LUA_REGISTRYINDEX as int, &ARRAY_KEY as *u8);
+            }
+        }
+        state.set_metatable(-2);
+    }
+
+    /// Parse a string, starting at its opening quote.
+    fn string(&mut self) -> Result<Vec<u8>, String> {
+        self.pos += 1;
+        let mut s = Vec::new();
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => return self.error("unterminated string"),
+            };
+            match c {
+                '"' => {
+                    self.pos += 1;
+                    return Ok(s);
+                }
+                '\\' => {
+                    self.pos += 1;
+                    let escaped = match self.peek() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('/') => '/',
+                        Some('b') => '\x08',
+                        Some('f') => '\x0c',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => {
+                            self.pos += 1;
+                            let c = try!(self.unicode_escape());
+                            s.push_all(str::from_char(c).as_bytes());
+                            continue;
+                        }
+                        _ => return self.error("invalid escape sequence"),
+                    };
+                    s.push(escaped as u8);
+                    self.pos += 1;
+                }
+                '\x00'..'\x1f' => return self.error("control character in string"),
+                _ => {
+                    s.push(self.src[self.pos]);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    /// Parse the `XXXX` of a `\uXXXX` escape, and the low surrogate following a high one.
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let high = try!(self.hex4());
+        let code = match high {
+            0xD800..0xDBFF => {
+                if !self.src.slice_from(self.pos).starts_with(bytes!("\\u")) {
+                    return self.error("missing low surrogate");
+                }
+                self.pos += 2;
+                let low = try!(self.hex4());
+                if low < 0xDC00 || low > 0xDFFF {
+                    return self.error("invalid low surrogate");
+                }
+                0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+            }
+            0xDC00..0xDFFF => return self.error("unexpected low surrogate"),
+            code => code,
+        };
+        match char::from_u32(code) {
+            Some(c) => Ok(c),
+            None => self.error("invalid unicode escape"),
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let mut code = 0;
+        for _ in range(0, 4) {
+            let digit = match self.peek().and_then(|c| c.to_digit(16)) {
+                Some(digit) => digit,
+                None => return self.error("invalid unicode escape"),
+            };
+            code = code * 16 + digit as u32;
+            self.pos += 1;
+        }
+        Ok(code)
+    }
+
+    fn number(&mut self) -> Result<(), String> {
+        let start = self.pos;
+        if self.peek() == Some('-') {
+            self.pos += 1;
+        }
+        match self.peek() {
+            Some('0') => self.pos += 1,
+            Some('1'..'9') => self.digits(),
+            _ => return self.unexpected(),
+        }
+        if self.peek() == Some('.') {
+            self.pos += 1;
+            if !self.peek().map_or(false, |c| c.is_digit()) {
+                return self.unexpected();
+            }
+            self.digits();
+        }
+        if self.peek() == Some('e') || self.peek() == Some('E') {
+            self.pos += 1;
+            if self.peek() == Some('+') || self.peek() == Some('-') {
+                self.pos += 1;
+            }
+            if !self.peek().map_or(false, |c| c.is_digit()) {
+                return self.unexpected();
+            }
+            self.digits();
+        }
+
+        let text = str::from_utf8(self.src.slice(start, self.pos)).unwrap();
+        match parse_number(text) {
+            Some(n) => {
+                self.lua.state.push_float(n);
+                Ok(())
+            }
+            None => self.error("invalid number"),
+        }
+    }
+
+    fn digits(&mut self) {
+        while self.peek().map_or(false, |c| c.is_digit()) {
+            self.pos += 1;
+        }
+    }
+
+    fn literal(&mut self, word: &str, push: |&State|) -> Result<(), String> {
+        if !self.src.slice_from(self.pos).starts_with(word.as_bytes()) {
+            return self.unexpected();
+        }
+        self.pos += word.len();
+        push(&self.lua.state);
+        Ok(())
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        match self.peek() {
+            Some(found) if found == c => {
+                self.pos += 1;
+                Ok(())
+            }
+            _ => self.unexpected(),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        loop {
+            match self.peek() {
+                Some(' ') | Some('\t') | Some('\n') | Some('\r') => self.pos += 1,
+                _ => break,
+            }
+        }
+    }
+
+    /// Get the current byte as a character.
+    fn peek(&self) -> Option<char> {
+        match self.pos < self.src.len() {
+            true => Some(self.src[self.pos] as char),
+            false => None,
+        }
+    }
+
+    fn unexpected<T>(&self) -> Result<T, String> {
+        match self.peek() {
+            Some(c) if c >= ' ' && c <= '~' => self.error(format!("unexpected character '{}'", c).as_slice()),
+            Some(c) => self.error(format!("unexpected byte 0x{:02x}", c as u8).as_slice()),
+            None => self.error("unexpected end of input"),
+        }
+    }
+
+    /// Fail with a message giving the current line and column.
+    fn error<T>(&self, msg: &str) -> Result<T, String> {
+        let before = self.src.slice_to(self.pos);
+        let line = before.iter().filter(|&&c| c == '\n' as u8).count() + 1;
+        let column = match before.iter().rposition(|&c| c == '\n' as u8) {
+            Some(newline) => self.pos - newline,
+            None => self.pos + 1,
+        };
+        Err(format!("{} at line {}, column {}", msg, line, column))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use lua::Lua;
+    use status::LuaOk;
+
+    use super::open;
+
+    #[test]
+    fn test_json()
+    {
+        let lua = Lua::new();
+        lua.state.load_stdlibs();
+        assert!(lua.register_module("json", open));
+        assert_eq!(lua.exec_str(r#"
+            local json = require('json')
+            local value = json.decode('{"a": [1, 2.5, "x\\u00e9"], "b": null, "c": {}}')
+            assert(value.a[2] == 2.5 and value.a[3] == 'x\195\169')
+            assert(value.b == json.null)
+            compact = json.encode({a = {1, 2}, b = json.null, c = 'q"'}, {sort_keys = true})
+            pretty = json.encode({1, {x = true}}, {indent = 2})
+
+            local ok, err = pcall(json.decode, '[1,\n 2,]')
+            decode_error = err
+        "#), LuaOk);
+        assert_eq!(lua.get_global("compact"), Some("{\"a\":[1,2],\"b\":null,\"c\":\"q\\\"\"}".to_string()));
+        assert_eq!(lua.get_global("pretty"), Some("[\n  1,\n  {\n    \"x\": true\n  }\n]".to_string()));
+        assert_eq!(lua.get_global("decode_error"), Some("json: unexpected character ']' at line 2, column 4".to_string()));
+    }
+
+    #[test]
+    fn test_json_arrays()
+    {
+        let lua = Lua::new();
+        lua.state.load_stdlibs();
+        assert!(lua.register_module("json", open));
+        assert_eq!(lua.exec_str(r#"
+            local json = require('json')
+            empty = json.encode(json.decode('[]'))
+            nested = json.encode(json.decode('{"a": [], "b": [[]], "c": {}}'), {sort_keys = true})
+
+            for _, indent in ipairs({-1, 1.5, 1 / 0}) do
+                local ok, err = pcall(json.encode, {}, {indent = indent})
+                assert(not ok and err:find('invalid indent'))
+            end
+        "#), LuaOk);
+        assert_eq!(lua.get_global("empty"), Some("[]".to_string()));
+        assert_eq!(lua.get_global("nested"), Some("{\"a\":[],\"b\":[[]],\"c\":{}}".to_string()));
+    }
+
+    #[test]
+    fn test_json_numbers()
+    {
+        let lua = Lua::new();
+        lua.state.load_stdlibs();
+        assert!(lua.register_module("json", open));
+        assert_eq!(lua.exec_str(r#"
+            local json = require('json')
+            text = json.encode({id = 123456789012345, ratio = 0.1, third = 1 / 3, [2^60] = 1}, {sort_keys = true})
+            local value = json.decode(text)
+            assert(value.id == 123456789012345 and value.third == 1 / 3)
+        "#), LuaOk);
+        assert_eq!(lua.get_global("text"),
+                   Some("{\"1.152921504606847e+18\":1,\"id\":123456789012345,\"ratio\":0.1,\"third\":0.3333333333333333}".to_string()));
+    }
+}
@@ -7,7 +7,13 @@
 #![feature(macro_rules)]
 
 extern crate collections;
+extern crate serialize;
 extern crate libc;
+extern crate time;
+
+use std::str::raw;
+
+use libc::c_char;
 
 pub use lua::Lua;
 
@@ -16,9 +22,34 @@ pub mod ffi;
 
 pub mod lua;
 pub mod state;
+pub mod load;
+pub mod dump;
+pub mod bytecode;
+pub mod opcodes;
+pub mod disasm;
+pub mod cache;
+pub mod vfs;
+pub mod stdlib;
+pub mod debug;
+pub mod profiler;
+pub mod coverage;
+pub mod interrupt;
 
 pub mod traits;
+pub mod codec;
+pub mod json;
+pub mod pretty;
 pub mod types;
 pub mod status;
 
+mod serial;
+
 pub mod macros;
+
+/// Convert a nullable C string to an owned string.
+fn from_c_str(s: *c_char) -> Option<String> {
+    match s.is_null() {
+        true => None,
+        false => Some(unsafe { raw::from_c_str(s) }),
+    }
+}
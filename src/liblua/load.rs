@@ -0,0 +1,78 @@
+use std::io::{IoError, Reader, EndOfFile};
+use std::mem::transmute;
+use std::ptr::null;
+
+use libc::{c_char, c_void, size_t};
+
+use ffi;
+
+/// Kind of chunks accepted when loading Lua code.
+#[deriving(Show, Eq, Clone)]
+pub enum LoadMode {
+    /// Only source code.
+    Text,
+    /// Only precompiled chunks, as produced by `lua_dump`.
+    Binary,
+    Both,
+}
+
+impl LoadMode {
+    /// Translate the mode into the string expected by Lua loading functions.
+    pub fn to_lua(&self) -> &'static str {
+        match *self {
+            Text => "t",
+            Binary => "b",
+            Both => "bt",
+        }
+    }
+
+    /// Translate a mode string of Lua loading functions.
+    pub fn from_lua(mode: &str) -> Option<LoadMode> {
+        match mode {
+            "t" => Some(Text),
+            "b" => Some(Binary),
+            "bt" => Some(Both),
+            _ => None,
+        }
+    }
+}
+
+static READER_BUFSIZE: uint = 4096;
+
+/// State of a Rust reader used by `lua_load`.
+pub struct ReaderData<'a> {
+    reader: &'a mut Reader,
+    buf: [u8, ..READER_BUFSIZE],
+    /// First error other than end of file, if any.
+    pub error: Option<IoError>,
+}
+
+impl<'a> ReaderData<'a> {
+    pub fn new(reader: &'a mut Reader) -> ReaderData<'a> {
+        ReaderData {
+            reader: reader,
+            buf: [0, ..READER_BUFSIZE],
+            error: None,
+        }
+    }
+}
+
+/// `lua_Reader` callback reading from a `ReaderData`.
+pub extern "C" fn _lua_reader(_: *ffi::lua_State, ud: *c_void, sz: *size_t) -> *c_char {
+    let data: &mut ReaderData = unsafe { transmute(ud) };
+    let sz: &mut size_t = unsafe { transmute(sz) };
+
+    match data.reader.read(data.buf) {
+        Ok(n) => {
+            *sz = n as size_t;
+            data.buf.as_ptr() as *c_char
+        }
+        Err(e) => {
+            if e.kind != EndOfFile {
+                data.error = Some(e);
+            }
+            *sz = 0;
+            null()
+        }
+    }
+}
@@ -1,7 +1,48 @@
+use std::cell::Cell;
+use std::mem::{size_of, transmute};
+use std::ptr;
+use std::io::{File, IoError, IoResult, InvalidInput, MemWriter, Reader, Writer};
+
+use libc::c_int;
+use serialize::{Encodable, Decodable};
+
+use ffi;
 use state::State;
+use load::{LoadMode, Binary, Both};
+use bytecode::Chunk;
+use cache::ChunkCache;
+use debug::{ActivationRecord, DebugEvent, DebugInfo, Frames, HookMask};
+use profiler::Profiler;
+use coverage::Coverage;
+use interrupt::InterruptHandle;
+use vfs::{EmbeddedFs, _lua_embedded_searcher};
 use traits::{FromLua, ToLua};
+use codec::{CodecError, LuaEncoder, LuaDecoder};
+use types::{LuaTable, LuaUserData};
 use status::{LuaStatus, LuaOk};
 
+/**
+ *  Debug hook closure.
+ *
+ *  Returning an error message raises a Lua error from the hook.
+ */
+pub type Hook<'a> = |&Lua, DebugEvent, &mut ActivationRecord|: 'a -> Result<(), String>;
+
+/// Hook closure, with the events it's called for.
+struct HookData<'a> {
+    hook: Hook<'a>,
+    mask: HookMask,
+    count: int,
+}
+
+// Address used as registry key for the current hook, a userdata owning its `HookData`.
+static HOOK_KEY: u8 = 0;
+
+// Address used as registry key for the load mode.
+static LOAD_MODE_KEY: u8 = 0;
+
+static UTF8_BOM: &'static [u8] = bytes!(0xEF, 0xBB, 0xBF);
+
 /**
  *  Proxy to Lua general functions.
  *
@@ -9,15 +50,45 @@ use status::{LuaStatus, LuaOk};
  */
 pub struct Lua<'a> {
     pub state: State<'a>,
+    chunk_cache: Cell<Option<&'a ChunkCache>>,
 }
 
 impl<'a> Lua<'a> {
     pub fn new() -> Lua {
+        Lua::from_state(State::new())
+    }
+
+    /// Wrap an existing state.
+    pub fn from_state(state: State<'a>) -> Lua<'a> {
         Lua {
-            state: State::new(),
+            state: state,
+            chunk_cache: Cell::new(None),
         }
     }
 
+    /// Get the kind of chunks accepted when loading code.
+    pub fn load_mode(&self) -> LoadMode {
+        self.state.raw_get_ptr(ffi::LUA_REGISTRYINDEX as int, &LOAD_MODE_KEY as *u8);
+        let mode: Option<String> = self.pop();
+        mode.and_then(|mode| LoadMode::from_lua(mode.as_slice())).unwrap_or(Both)
+    }
+
+    /**
+     *  Set the kind of chunks accepted when loading code.
+     *
+     *  Untrusted code should be restricted to `Text`, as malicious bytecode can crash the VM.
+     *  The mode is kept in the registry, so it also applies to Rust searchers and callbacks.
+     */
+    pub fn set_load_mode(&self, mode: LoadMode) {
+        self.push(mode.to_lua());
+        self.state.raw_set_ptr(ffi::LUA_REGISTRYINDEX as int, &LOAD_MODE_KEY as *u8);
+    }
+
+    /// Set the cache used to load files executed by `exec_file`.
+    pub fn set_chunk_cache(&self, cache: &'a ChunkCache) {
+        self.chunk_cache.set(Some(cache));
+    }
+
     /// Push a Rust value to the Lua stack.
     pub fn push<T: ToLua>(&self, val: T) {
         val.to_lua(&self.state);
@@ -58,21 +129,492 @@ impl<'a> Lua<'a> {
         self.state.set_global(name);
     }
 
+    /**
+     *  Push a Rust value implementing `Encodable`, converted as described in `LuaEncoder`.
+     *
+     *  Nothing is pushed on error.
+     */
+    pub fn push_encodable<'b, T: Encodable<LuaEncoder<'b, 'a>, CodecError>>(&'b self, val: &T) -> Result<(), CodecError> {
+        let top = self.state.get_top();
+        let result = val.encode(&mut LuaEncoder::new(&self.state));
+        if result.is_err() {
+            self.state.set_top(top);
+        }
+        result
+    }
+
+    /// Get a Rust value implementing `Decodable` from the Lua stack.
+    pub fn get_decodable<'b, T: Decodable<LuaDecoder<'b, 'a>, CodecError>>(&'b self, idx: int) -> Result<T, CodecError> {
+        self.state.push_value(idx);
+        let result = Decodable::decode(&mut LuaDecoder::new(&self.state));
+        self.state.pop(1);
+        result
+    }
+
+    /// Push a new table containing the given functions, like `luaL_newlib`.
+    pub fn new_lib(&self, funcs: &[(&str, fn(&Lua) -> int)]) {
+        self.state.create_table(0, funcs.len() as int);
+        for &(name, f) in funcs.iter() {
+            self.state.push_function(f);
+            self.state.set_field(-2, name);
+        }
+    }
+
+    /**
+     *  Register a Rust module, loadable by scripts with `require`.
+     *
+     *  `f` is called by `require` with the module name as first argument, and should push the module value.
+     *  Return false if the `package` library isn't loaded.
+     */
+    pub fn register_module(&self, name: &str, f: fn(&Lua) -> int) -> bool {
+        if !self.get_package_field("preload") {
+            return false;
+        }
+
+        self.state.push_function(f);
+        self.state.set_field(-2, name);
+        self.state.pop(1);
+        true
+    }
+
+    /**
+     *  Add a `require` searcher, tried after the default ones.
+     *
+     *  `f` is called with the module name, and should return a loader function, or an error string
+     *  explaining why the module wasn't found.
+     *  Return false if the `package` library isn't loaded.
+     */
+    pub fn add_searcher(&self, f: fn(&Lua) -> int) -> bool {
+        if !self.get_package_field("searchers") {
+            return false;
+        }
+
+        let n = self.state.len(-1);
+        self.state.push_function(f);
+        self.state.raw_set_index(-2, n + 1);
+        self.state.pop(1);
+        true
+    }
+
+    /**
+     *  Make modules of an embedded file tree loadable with `require`.
+     *
+     *  The tree is searched right after `package.preload`, before the real filesystem.
+     *  Return false if the `package` library isn't loaded.
+     */
+    pub fn add_embedded_fs(&self, fs: &'a EmbeddedFs<'a>) -> bool {
+        if !self.get_package_field("searchers") {
+            return false;
+        }
+
+        // Shift every searcher but preload.
+        let mut i = self.state.len(-1);
+        while i >= 2 {
+            self.state.raw_get_index(-1, i);
+            self.state.raw_set_index(-2, i + 1);
+            i -= 1;
+        }
+
+        self.state.push_userdata(fs as *EmbeddedFs);
+        self.state.push_cclosure(_lua_embedded_searcher, 1);
+        self.state.raw_set_index(-2, 2);
+        self.state.pop(1);
+        true
+    }
+
+    /// Push a table field of the `package` library, if available.
+    fn get_package_field(&self, name: &str) -> bool {
+        self.state.get_global("package");
+        match self.state.get_type(-1) {
+            LuaTable => {}
+            _ => {
+                self.state.pop(1);
+                return false;
+            }
+        }
+
+        self.state.get_field(-1, name);
+        self.state.remove(-2);
+        match self.state.get_type(-1) {
+            LuaTable => true,
+            _ => {
+                self.state.pop(1);
+                false
+            }
+        }
+    }
+
     /// Execute a file then return a status.
     pub fn exec_file(&self, filename: &str) -> LuaStatus {
-        match self.state.load_file(filename) {
+        match self.load_file(filename) {
             LuaOk => self.state.exec(),
             status => status,
         }
     }
 
+    /// Load a file, through the chunk cache if any, pushing the chunk or the error message.
+    pub fn load_file(&self, filename: &str) -> LuaStatus {
+        // Cached chunks are binary while sources are text, so the cache is only usable when both are accepted.
+        let cache = match (self.chunk_cache.get(), self.load_mode()) {
+            (Some(cache), Both) => cache,
+            (_, mode) => return self.state.load_filex(filename, mode),
+        };
+
+        let mut source = match File::open(&Path::new(filename)).read_to_end() {
+            Ok(source) => source,
+            // Let Lua report the error.
+            Err(_) => return self.state.load_filex(filename, self.load_mode()),
+        };
+        let chunkname = format!("@{}", filename);
+
+        // Skip an UTF-8 BOM, then the first line if it's a comment (e.g. `#!/usr/bin/lua`), keeping
+        // line numbers, like `luaL_loadfile`.
+        if source.as_slice().starts_with(UTF8_BOM) {
+            source = Vec::from_slice(source.slice_from(UTF8_BOM.len()));
+        }
+        if source.as_slice().starts_with(bytes!("#")) {
+            let end = source.iter().position(|&c| c == '\n' as u8).unwrap_or(source.len());
+            source = Vec::from_slice(source.slice_from(end));
+        }
+
+        let key = ChunkCache::key(source.as_slice(), chunkname.as_slice());
+        match cache.get(key) {
+            Some(bytes) => match self.state.load_buffer(bytes.as_slice(), chunkname.as_slice(), Binary) {
+                LuaOk => return LuaOk,
+                // Produced by an incompatible Lua, compile it again.
+                _ => self.state.pop(1),
+            },
+            None => {}
+        }
+
+        match self.state.load_buffer(source.as_slice(), chunkname.as_slice(), Both) {
+            LuaOk => {
+                match self.dump(false) {
+                    Ok(bytes) => cache.put(key, bytes),
+                    Err(_) => {}
+                }
+                LuaOk
+            }
+            status => status,
+        }
+    }
+
     /// Execute a string then return a status.
     pub fn exec_str(&self, source: &str) -> LuaStatus {
-        match self.state.load_str(source) {
+        match self.state.load_strx(source, self.load_mode()) {
+            LuaOk => self.state.exec(),
+            status => status,
+        }
+    }
+
+    /// Execute a buffer as a chunk named `chunkname`, then return a status.
+    pub fn exec_buffer(&self, buf: &[u8], chunkname: &str) -> LuaStatus {
+        match self.state.load_buffer(buf, chunkname, self.load_mode()) {
+            LuaOk => self.state.exec(),
+            status => status,
+        }
+    }
+
+    /// Execute a chunk named `chunkname` streamed from a reader, then return a status.
+    pub fn exec_reader<R: Reader>(&self, reader: &mut R, chunkname: &str) -> LuaStatus {
+        match self.state.load_reader(reader, chunkname, self.load_mode()) {
+            LuaOk => self.state.exec(),
+            status => status,
+        }
+    }
+
+    /**
+     *  Dump the Lua function at the top of the stack as a precompiled chunk into a writer.
+     *
+     *  Debug information (source, line numbers, local names) is removed when `strip` is true.
+     */
+    pub fn dump_to<W: Writer>(&self, w: &mut W, strip: bool) -> IoResult<()> {
+        if !strip {
+            return self.state.dump(w);
+        }
+
+        let bytes = try!(self.dump(false));
+        match Chunk::parse(bytes.as_slice()) {
+            Ok(mut chunk) => {
+                chunk.strip();
+                chunk.write(w)
+            }
+            Err(msg) => Err(IoError {
+                kind: InvalidInput,
+                desc: "unable to strip precompiled chunk",
+                detail: Some(msg),
+            }),
+        }
+    }
+
+    /// Dump the Lua function at the top of the stack as a precompiled chunk.
+    pub fn dump(&self, strip: bool) -> IoResult<Vec<u8>> {
+        let mut w = MemWriter::new();
+        try!(self.dump_to(&mut w, strip));
+        Ok(w.unwrap())
+    }
+
+    /**
+     *  Load a string as a chunk whose environment is the table at index `env`, instead of the globals.
+     *
+     *  The loaded function is pushed to the stack, as with `State::load_str`.
+     */
+    pub fn load_with_env(&self, source: &str, env: int) -> LuaStatus {
+        let env = self.state.abs_index(env);
+
+        match self.state.load_strx(source, self.load_mode()) {
+            LuaOk => {
+                // The first upvalue of a main chunk is always _ENV.
+                self.state.push_value(env);
+                if self.state.set_upvalue(-2, 1).is_none() {
+                    self.state.pop(1);
+                }
+                LuaOk
+            }
+            status => status,
+        }
+    }
+
+    /// Execute a string with the table at index `env` as environment, then return a status.
+    pub fn exec_with_env(&self, source: &str, env: int) -> LuaStatus {
+        match self.load_with_env(source, env) {
             LuaOk => self.state.exec(),
             status => status,
         }
     }
+
+    /// Iterate over the activation records of the current call stack.
+    pub fn stack_frames<'b>(&'b self) -> Frames<'a, 'b> {
+        Frames::new(&self.state)
+    }
+
+    /// Get information about every function of the current call stack.
+    pub fn backtrace(&self) -> Vec<DebugInfo> {
+        self.stack_frames().map(|mut ar| ar.info(&self.state)).collect()
+    }
+
+    /**
+     *  Get the name and value of the local variable `n` (starting at 1) of a stack frame.
+     *
+     *  The value is None when it can't be converted to `T`.
+     */
+    pub fn get_local<T: FromLua>(&self, ar: &ActivationRecord, n: int) -> Option<(String, Option<T>)> {
+        self.state.get_local(ar, n).map(|name| (name, self.pop()))
+    }
+
+    /// Set the value of the local variable `n` of a stack frame, then return its name.
+    pub fn set_local<T: ToLua>(&self, ar: &ActivationRecord, n: int, val: T) -> Option<String> {
+        self.push(val);
+        let name = self.state.set_local(ar, n);
+        if name.is_none() {
+            self.state.pop(1);
+        }
+        name
+    }
+
+    /// Get the names of the active local variables of a stack frame, including temporaries.
+    pub fn local_names(&self, ar: &ActivationRecord) -> Vec<String> {
+        let mut names = Vec::new();
+        let mut n = 1;
+        loop {
+            match self.state.get_local(ar, n) {
+                Some(name) => {
+                    self.state.pop(1);
+                    names.push(name);
+                }
+                None => break,
+            }
+            n += 1;
+        }
+        names
+    }
+
+    /**
+     *  Get the name and value of the upvalue `n` (starting at 1) of the function at index `funcindex`.
+     *
+     *  The value is None when it can't be converted to `T`.
+     */
+    pub fn get_upvalue<T: FromLua>(&self, funcindex: int, n: int) -> Option<(String, Option<T>)> {
+        self.state.get_upvalue(funcindex, n).map(|name| (name, self.pop()))
+    }
+
+    /// Set the value of the upvalue `n` of the function at index `funcindex`, then return its name.
+    pub fn set_upvalue<T: ToLua>(&self, funcindex: int, n: int, val: T) -> Option<String> {
+        // Account for the pushed value when using a relative index.
+        let funcindex = if funcindex < 0 && funcindex > ffi::LUA_REGISTRYINDEX as int {
+            funcindex - 1
+        } else {
+            funcindex
+        };
+
+        self.push(val);
+        let name = self.state.set_upvalue(funcindex, n);
+        if name.is_none() {
+            self.state.pop(1);
+        }
+        name
+    }
+
+    /// Get the names of the upvalues of the function at index `funcindex`.
+    pub fn upvalue_names(&self, funcindex: int) -> Vec<String> {
+        let mut names = Vec::new();
+        let mut n = 1;
+        loop {
+            match self.state.get_upvalue(funcindex, n) {
+                Some(name) => {
+                    self.state.pop(1);
+                    names.push(name);
+                }
+                None => break,
+            }
+            n += 1;
+        }
+        names
+    }
+
+    /**
+     *  Set a debug hook, called for the events in `mask`.
+     *
+     *  `count` is only meaningful when `mask` contains `MASK_COUNT`.
+     *  Replace the previous hook, if any. Hooks can be replaced or removed from a hook.
+     */
+    pub fn set_hook(&self, mask: HookMask, count: int, hook: Hook<'a>) {
+        let data = box HookData {
+            hook: hook,
+            mask: mask,
+            count: count,
+        };
+
+        // The hook is owned by the registry, and freed when collected: the hook being run
+        // is kept on the stack until it returns.
+        let ud = self.state.new_userdata(size_of::<*mut HookData<'a>>()) as *mut *mut HookData<'a>;
+        unsafe {
+            *ud = transmute(data);
+        }
+        self.state.new_table();
+        self.state.push_cfunction(_lua_hook_gc);
+        self.state.set_field(-2, "__gc");
+        self.state.set_metatable(-2);
+
+        self.state.raw_set_ptr(ffi::LUA_REGISTRYINDEX as int, &HOOK_KEY as *u8);
+        self.state.set_hook(_lua_hook, mask, count);
+    }
+
+    /**
+     *  Reinstall the hook set by `set_hook`, after another hook replaced it through the raw API.
+     *
+     *  Remove the current hook if there's none.
+     */
+    pub fn restore_hook(&self) {
+        self.state.raw_get_ptr(ffi::LUA_REGISTRYINDEX as int, &HOOK_KEY as *u8);
+        let data = hook_data(&self.state, -1);
+        self.state.pop(1);
+        match data {
+            Some(data) => {
+                let data = unsafe { &*data };
+                self.state.set_hook(_lua_hook, data.mask, data.count);
+            }
+            None => self.state.remove_hook(),
+        }
+    }
+
+    /// Start profiling with the given profiler, replacing the current hook.
+    pub fn set_profiler(&self, profiler: &'a Profiler) {
+        let (mask, count) = profiler.hook_mask();
+        self.set_hook(mask, count, |lua, event, ar| {
+            profiler.on_event(lua, event, ar);
+            Ok(())
+        });
+    }
+
+    /// Start collecting line coverage, replacing the current hook.
+    pub fn set_coverage(&self, coverage: &'a Coverage) {
+        self.set_hook(coverage.hook_mask(), 0, |lua, event, ar| {
+            coverage.on_event(lua, event, ar);
+            Ok(())
+        });
+    }
+
+    /// Remove the current debug hook.
+    pub fn remove_hook(&self) {
+        self.state.remove_hook();
+
+        self.state.push_nil();
+        self.state.raw_set_ptr(ffi::LUA_REGISTRYINDEX as int, &HOOK_KEY as *u8);
+    }
+
+    /**
+     *  Get a handle interrupting the scripts run by this state.
+     *
+     *  Marked as unsafe as the handle isn't tied to the state lifetime, so it can be used from
+     *  a signal handler or another task: it must not be used once this state is closed.
+     */
+    pub unsafe fn interrupt_handle(&self) -> InterruptHandle {
+        InterruptHandle::from_ffi(self.state.as_ffi())
+    }
+}
+
+/// Get the hook owned by the userdata at the given index, if any.
+fn hook_data(state: &State, idx: int) -> Option<*mut HookData<'static>> {
+    match state.get_type(idx) {
+        LuaUserData => {}
+        _ => return None,
+    }
+    let ud: *mut *mut HookData = state.get_userdata::<*mut HookData>(idx) as *mut *mut HookData;
+    let data = unsafe { *ud };
+    match data.is_null() {
+        true => None,
+        false => Some(data),
+    }
+}
+
+extern "C" fn _lua_hook(raw: *ffi::lua_State, ar: *ffi::lua_Debug) {
+    let lua = Lua::from_state(unsafe {
+        State::from_ffi(raw)
+    });
+
+    // Kept on the stack while the hook runs, so it isn't collected if the hook is replaced.
+    lua.state.raw_get_ptr(ffi::LUA_REGISTRYINDEX as int, &HOOK_KEY as *u8);
+    let data = match hook_data(&lua.state, -1) {
+        Some(data) => data,
+        None => {
+            lua.state.pop(1);
+            return;
+        }
+    };
+
+    let failed = {
+        let data = unsafe { &mut *data };
+        let mut ar = unsafe { ActivationRecord::from_ffi(ar) };
+        let event = ar.event();
+
+        match (data.hook)(&lua, event, &mut ar) {
+            Ok(()) => false,
+            Err(msg) => {
+                lua.push(msg);
+                true
+            }
+        }
+    };
+
+    if failed {
+        lua.state.error();
+    }
+    lua.state.pop(1);
+}
+
+/// `__gc` metamethod of the userdata owning a hook.
+extern "C" fn _lua_hook_gc(raw: *ffi::lua_State) -> c_int {
+    let state = unsafe { State::from_ffi(raw) };
+    let ud: *mut *mut HookData = state.get_userdata::<*mut HookData>(1) as *mut *mut HookData;
+    unsafe {
+        if !(*ud).is_null() {
+            let data: Box<HookData> = transmute(*ud);
+            drop(data);
+            *ud = ptr::mut_null();
+        }
+    }
+    0
 }
 
 #[cfg(test)]
@@ -88,4 +630,231 @@ mod test {
         "), ::status::LuaOk);
         assert_eq!(lua.get_global("a"), Some(25));
     }
+
+    #[test]
+    fn test_exec_with_env()
+    {
+        let lua = Lua::new();
+        lua.state.new_table();
+        assert_eq!(lua.exec_with_env("a = 25", -1), ::status::LuaOk);
+        assert_eq!(lua.get_global::<int>("a"), None);
+
+        lua.state.get_field(-2, "a");
+        assert_eq!(lua.pop(), Some(25));
+    }
+
+    #[test]
+    fn test_exec_reader()
+    {
+        let lua = Lua::new();
+        let mut reader = ::std::io::BufReader::new(bytes!("a = 25"));
+        assert_eq!(lua.exec_reader(&mut reader, "@test.lua"), ::status::LuaOk);
+        assert_eq!(lua.get_global("a"), Some(25));
+    }
+
+    #[test]
+    fn test_dump()
+    {
+        let lua = Lua::new();
+        assert_eq!(lua.state.load_str("a = 25"), ::status::LuaOk);
+        let bytes = lua.dump(true).unwrap();
+        lua.state.pop(1);
+
+        assert_eq!(lua.exec_buffer(bytes.as_slice(), "=dump"), ::status::LuaOk);
+        assert_eq!(lua.get_global("a"), Some(25));
+    }
+
+    fn test_module(lua: &Lua) -> int {
+        lua.state.new_table();
+        lua.push(42);
+        lua.state.set_field(-2, "answer");
+        1
+    }
+
+    #[test]
+    fn test_register_module()
+    {
+        let lua = Lua::new();
+        lua.state.load_stdlibs();
+        assert!(lua.register_module("test", test_module));
+        assert_eq!(lua.exec_str("
+            answer = require('test').answer
+        "), ::status::LuaOk);
+        assert_eq!(lua.get_global("answer"), Some(42));
+    }
+
+    #[test]
+    fn test_embedded_fs()
+    {
+        let mut fs = ::vfs::EmbeddedFs::new("scripts");
+        fs.add("a/b.lua", bytes!("return { name = ... }"));
+
+        let lua = Lua::new();
+        lua.state.load_stdlibs();
+        assert!(lua.add_embedded_fs(&fs));
+        assert_eq!(lua.exec_str("
+            name = require('a.b').name
+        "), ::status::LuaOk);
+        assert_eq!(lua.get_global("name"), Some("a.b".to_string()));
+    }
+
+    #[test]
+    fn test_load_mode()
+    {
+        let bytes = {
+            let lua = Lua::new();
+            assert_eq!(lua.state.load_str("return 42"), ::status::LuaOk);
+            lua.dump(false).unwrap()
+        };
+        let mut fs = ::vfs::EmbeddedFs::new("scripts");
+        fs.add("compiled.lua", bytes.as_slice());
+
+        let lua = Lua::new();
+        lua.state.load_stdlibs();
+        assert!(lua.add_embedded_fs(&fs));
+
+        // Searchers see the mode of the state.
+        lua.set_load_mode(::load::Text);
+        assert_eq!(lua.load_mode(), ::load::Text);
+        assert_eq!(lua.exec_str("loaded = pcall(require, 'compiled')"), ::status::LuaOk);
+        assert_eq!(lua.get_global("loaded"), Some(false));
+
+        lua.set_load_mode(::load::Both);
+        assert_eq!(lua.exec_str("answer = require('compiled')"), ::status::LuaOk);
+        assert_eq!(lua.get_global("answer"), Some(42));
+    }
+
+    #[test]
+    fn test_safe_stdlibs()
+    {
+        let lua = Lua::new();
+        lua.state.load_safe_stdlibs();
+        assert_eq!(lua.exec_str("
+            assert(io == nil and debug == nil and package == nil)
+            assert(dofile == nil and loadfile == nil and require == nil)
+            for _, name in ipairs({'execute', 'exit', 'getenv', 'remove', 'rename', 'setlocale', 'tmpname'}) do
+                assert(os[name] == nil, name)
+            end
+            assert(os.time() and os.clock() and os.date('%Y') and os.difftime(2, 1) == 1)
+            assert(not load(string.dump(function() end)))
+            assert(load('return 1')() == 1)
+        "), ::status::LuaOk);
+    }
+
+    fn bump_first_local(lua: &Lua) -> int {
+        let ar = lua.state.get_stack(1).unwrap();
+        let names = lua.local_names(&ar);
+        let (_, value) = lua.get_local::<int>(&ar, 1).unwrap();
+        lua.set_local(&ar, 1, value.unwrap() + 1);
+        lua.push(names.as_slice().connect(","));
+        1
+    }
+
+    #[test]
+    fn test_locals()
+    {
+        let lua = Lua::new();
+        lua.state.push_function(bump_first_local);
+        lua.state.set_global("bump_first_local");
+        assert_eq!(lua.exec_str("
+            local x, y = 1, 2
+            names = bump_first_local()
+            result = x
+        "), ::status::LuaOk);
+        assert_eq!(lua.get_global("names"), Some("x,y".to_string()));
+        assert_eq!(lua.get_global("result"), Some(2));
+    }
+
+    #[test]
+    fn test_upvalues()
+    {
+        let lua = Lua::new();
+        assert_eq!(lua.exec_str("
+            local a, b = 1, 2
+            function f() return a end
+            function g() return b end
+        "), ::status::LuaOk);
+
+        lua.state.get_global("f");
+        assert_eq!(lua.upvalue_names(-1), vec!["a".to_string()]);
+        assert_eq!(lua.get_upvalue(-1, 1), Some(("a".to_string(), Some(1))));
+        assert_eq!(lua.set_upvalue(-1, 1, 10), Some("a".to_string()));
+        assert_eq!(lua.get_upvalue(-1, 1), Some(("a".to_string(), Some(10))));
+        assert_eq!(lua.get_upvalue::<int>(-1, 2), None);
+        assert!(lua.state.upvalue_id(-1, 2).is_none());
+
+        lua.state.get_global("g");
+        assert!(lua.state.upvalue_id(-2, 1) != lua.state.upvalue_id(-1, 1));
+        assert!(!lua.state.upvalue_join(-2, 2, -1, 1));
+        assert!(lua.state.upvalue_join(-2, 1, -1, 1));
+        assert_eq!(lua.state.upvalue_id(-2, 1), lua.state.upvalue_id(-1, 1));
+
+        // C functions can't be joined.
+        lua.state.push_function(bump_first_local);
+        assert!(!lua.state.upvalue_join(-1, 1, -2, 1));
+        lua.state.pop(3);
+
+        assert_eq!(lua.exec_str("result = f()"), ::status::LuaOk);
+        assert_eq!(lua.get_global("result"), Some(2));
+    }
+
+    #[test]
+    fn test_hook()
+    {
+        let mut lines = Vec::new();
+        {
+            let lua = Lua::new();
+            lua.set_hook(::debug::MASK_LINE, 0, |_, event, _| {
+                match event {
+                    ::debug::HookLine(line) => lines.push(line),
+                    _ => {}
+                }
+                Ok(())
+            });
+            assert_eq!(lua.exec_str("local a = 1\nlocal b = 2"), ::status::LuaOk);
+        }
+        assert_eq!(lines, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_hook_error()
+    {
+        let lua = Lua::new();
+        lua.set_hook(::debug::MASK_LINE, 0, |_, event, _| {
+            match event {
+                ::debug::HookLine(2) => Err("stopped".to_string()),
+                _ => Ok(()),
+            }
+        });
+        assert_eq!(lua.exec_str("a = 1\na = 2"), ::status::LuaErr(::status::RuntimeError));
+        let msg: String = lua.pop().unwrap();
+        assert_eq!(msg.as_slice(), "stopped");
+        assert_eq!(lua.get_global("a"), Some(1));
+    }
+
+    #[test]
+    fn test_hook_replaced_from_hook()
+    {
+        let lua = Lua::new();
+
+        // Replaced by a hook failing on the next line, while the first one runs.
+        lua.set_hook(::debug::MASK_LINE, 0, |lua, _, _| {
+            lua.set_hook(::debug::MASK_LINE, 0, |_, _, _| Err("replaced".to_string()));
+            Ok(())
+        });
+        assert_eq!(lua.exec_str("a = 1\na = 2"), ::status::LuaErr(::status::RuntimeError));
+        let msg: String = lua.pop().unwrap();
+        assert_eq!(msg.as_slice(), "replaced");
+        assert_eq!(lua.get_global("a"), Some(1));
+        lua.state.pop(1);
+
+        // Removed while it runs.
+        lua.set_hook(::debug::MASK_LINE, 0, |lua, _, _| {
+            lua.remove_hook();
+            Ok(())
+        });
+        assert_eq!(lua.exec_str("a = 1\na = 2"), ::status::LuaOk);
+        lua.state.gc(::ffi::LUA_GCCOLLECT as int, 0);
+        assert_eq!(lua.exec_str("a = 3"), ::status::LuaOk);
+    }
 }
@@ -1,2 +1,194 @@
+use state::State;
+use traits::{FromLua, ToLua};
+use types::LuaNil;
 
-// TODO: Rewrite this.
+
+/**
+ *  Export a `luaopen_*` function, so the crate can be built as a native Lua module
+ *  loadable by a stock Lua interpreter with `require`.
+ *
+ *  The crate must be a dylib built with `--cfg lua_module`, so it uses the interpreter Lua symbols.
+ *  The function is given a `Lua` borrowing the interpreter state, and should push the module value.
+ *
+ *  ```ignore
+ *  fn open(lua: &Lua) -> int {
+ *      lua.new_lib([("hello", hello)]);
+ *      1
+ *  }
+ *
+ *  lua_module!(luaopen_hello, open)
+ *  ```
+ */
+#[macro_export]
+macro_rules! lua_module(
+    ($luaopen:ident, $open:expr) => (
+        #[no_mangle]
+        pub extern "C" fn $luaopen(raw: *::lua::ffi::lua_State) -> i32 {
+            let lua = ::lua::Lua::from_state(unsafe {
+                ::lua::state::State::from_ffi(raw)
+            });
+            let open: fn(&::lua::Lua) -> int = $open;
+
+            open(&lua) as i32
+        }
+    )
+)
+
+/**
+ *  Implement `ToLua` and `FromLua` for a struct, converted to a table with a key per field.
+ *
+ *  Fields are listed by name, optionally followed by `as "key"` to rename their table key,
+ *  and `= value` to give a default used when the key is missing.
+ *  `Option` fields are nil when `None`, and may be missing.
+ *
+ *  ```ignore
+ *  struct Config {
+ *      name: String,
+ *      size: uint,
+ *      comment: Option<String>,
+ *  }
+ *
+ *  lua_struct!(Config { name as "title", size = 10, comment })
+ *  ```
+ */
+#[macro_export]
+macro_rules! lua_struct(
+    ($name:ident { $($field:ident $(as $key:tt)* $(= $default:expr)*),* $(,)* }) => (
+        impl ::lua::traits::ToLua for $name {
+            fn to_lua(&self, state: &::lua::state::State) {
+                state.new_table();
+                $(
+                    ::lua::macros::set_field(state, ::lua::macros::field_key(stringify!($field), [$($key),*]),
+                                             &self.$field);
+                )*
+            }
+        }
+
+        impl ::lua::traits::FromLua for $name {
+            fn from_lua(state: &::lua::state::State, idx: int) -> Option<$name> {
+                match state.get_type(idx) {
+                    ::lua::types::LuaTable => {}
+                    _ => return None,
+                }
+                let idx = state.abs_index(idx);
+
+                Some($name {
+                    $(
+                        $field: match ::lua::macros::get_field(state, idx,
+                                                               ::lua::macros::field_key(stringify!($field), [$($key),*]),
+                                                               None $(.or(Some($default)))*) {
+                            Some(val) => val,
+                            None => return None,
+                        }
+                    ),*
+                })
+            }
+        }
+    )
+)
+
+/**
+ *  Implement `ToLua` and `FromLua` for an enum.
+ *
+ *  Variants without arguments are converted to their name, the other ones to tables holding
+ *  the variant name in the `tag` field, and the arguments in fields named as listed.
+ *  Variant names can be changed with `as "name"`.
+ *
+ *  ```ignore
+ *  enum Shape {
+ *      Point,
+ *      Circle(f64),
+ *  }
+ *
+ *  // `"point"` and `{tag = "Circle", radius = 2}`
+ *  lua_enum!(Shape { Point as "point", Circle(radius) })
+ *  ```
+ */
+#[macro_export]
+macro_rules! lua_enum(
+    ($name:ident { $($variant:ident $(($($arg:ident),*))* $(as $key:tt)*),* $(,)* }) => (
+        impl ::lua::traits::ToLua for $name {
+            fn to_lua(&self, state: &::lua::state::State) {
+                match *self {
+                    $(
+                        $variant $(($(ref $arg),*))* => {
+                            let tag = ::lua::macros::field_key(stringify!($variant), [$($key),*]);
+                            let args: &[&str] = &[$($(stringify!($arg)),*)*];
+                            if args.is_empty() {
+                                state.push_str(tag);
+                            } else {
+                                state.new_table();
+                                state.push_str(tag);
+                                state.set_field(-2, "tag");
+                                $($(::lua::macros::set_field(state, stringify!($arg), $arg);)*)*
+                            }
+                        }
+                    ),*
+                }
+            }
+        }
+
+        impl ::lua::traits::FromLua for $name {
+            fn from_lua(state: &::lua::state::State, idx: int) -> Option<$name> {
+                let idx = state.abs_index(idx);
+                let (tag, is_table): (String, bool) = match state.get_type(idx) {
+                    ::lua::types::LuaString => (state.get_str(idx), false),
+                    ::lua::types::LuaTable => match ::lua::macros::get_field(state, idx, "tag", None) {
+                        Some(tag) => (tag, true),
+                        None => return None,
+                    },
+                    _ => return None,
+                };
+
+                $(
+                    if tag.as_slice() == ::lua::macros::field_key(stringify!($variant), [$($key),*]) {
+                        let args: &[&str] = &[$($(stringify!($arg)),*)*];
+                        // Variants with arguments must be tables, the other ones strings.
+                        if args.is_empty() == is_table {
+                            return None;
+                        }
+
+                        return Some($variant $(($(
+                            match ::lua::macros::get_field(state, idx, stringify!($arg), None) {
+                                Some(val) => val,
+                                None => return None,
+                            }
+                        ),*))*);
+                    }
+                )*
+                None
+            }
+        }
+    )
+)
+
+/// Get the table key of a field, `rename` holding its new name if any. Used by `lua_struct!` and `lua_enum!`.
+#[doc(hidden)]
+pub fn field_key<'a>(name: &'a str, rename: &[&'a str]) -> &'a str {
+    match rename.last() {
+        Some(key) => *key,
+        None => name,
+    }
+}
+
+/**
+ *  Get the field `key` of the table at the absolute index `idx`, or `default` when nil.
+ *  Used by `lua_struct!` and `lua_enum!`.
+ */
+#[doc(hidden)]
+pub fn get_field<T: FromLua>(state: &State, idx: int, key: &str, default: Option<T>) -> Option<T> {
+    state.get_field(idx, key);
+    let val = match state.get_type(-1) {
+        LuaNil if default.is_some() => default,
+        _ => FromLua::from_lua(state, -1),
+    };
+    state.pop(1);
+    val
+}
+
+/// Set the field `key` of the table at the top of the stack. Used by `lua_struct!` and `lua_enum!`.
+#[doc(hidden)]
+pub fn set_field<T: ToLua>(state: &State, key: &str, val: &T) {
+    val.to_lua(state);
+    state.set_field(-2, key);
+}
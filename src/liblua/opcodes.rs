@@ -0,0 +1,256 @@
+static SIZE_OP: uint = 6;
+static SIZE_A: uint = 8;
+static SIZE_B: uint = 9;
+static SIZE_C: uint = 9;
+static SIZE_BX: uint = SIZE_B + SIZE_C;
+static SIZE_AX: uint = SIZE_A + SIZE_B + SIZE_C;
+
+static POS_A: uint = SIZE_OP;
+static POS_C: uint = POS_A + SIZE_A;
+static POS_B: uint = POS_C + SIZE_C;
+static POS_BX: uint = POS_C;
+static POS_AX: uint = POS_A;
+
+static MAXARG_SBX: int = ((1 << SIZE_BX) - 1) >> 1;
+
+/// Bit marking constant indices in RK arguments.
+static BITRK: uint = 1 << (SIZE_B - 1);
+
+/// Lua 5.2 virtual machine opcodes.
+#[deriving(Show, Eq, Clone)]
+pub enum OpCode {
+    OpMove,
+    OpLoadK,
+    OpLoadKx,
+    OpLoadBool,
+    OpLoadNil,
+    OpGetUpval,
+    OpGetTabUp,
+    OpGetTable,
+    OpSetTabUp,
+    OpSetUpval,
+    OpSetTable,
+    OpNewTable,
+    OpSelf,
+    OpAdd,
+    OpSub,
+    OpMul,
+    OpDiv,
+    OpMod,
+    OpPow,
+    OpUnm,
+    OpNot,
+    OpLen,
+    OpConcat,
+    OpJmp,
+    OpEq,
+    OpLt,
+    OpLe,
+    OpTest,
+    OpTestSet,
+    OpCall,
+    OpTailCall,
+    OpReturn,
+    OpForLoop,
+    OpForPrep,
+    OpTForCall,
+    OpTForLoop,
+    OpSetList,
+    OpClosure,
+    OpVararg,
+    OpExtraArg,
+}
+
+static OPCODES: [OpCode, ..40] = [
+    OpMove, OpLoadK, OpLoadKx, OpLoadBool, OpLoadNil, OpGetUpval, OpGetTabUp, OpGetTable,
+    OpSetTabUp, OpSetUpval, OpSetTable, OpNewTable, OpSelf, OpAdd, OpSub, OpMul,
+    OpDiv, OpMod, OpPow, OpUnm, OpNot, OpLen, OpConcat, OpJmp,
+    OpEq, OpLt, OpLe, OpTest, OpTestSet, OpCall, OpTailCall, OpReturn,
+    OpForLoop, OpForPrep, OpTForCall, OpTForLoop, OpSetList, OpClosure, OpVararg, OpExtraArg,
+];
+
+/// Instruction format of an opcode.
+#[deriving(Show, Eq, Clone)]
+pub enum OpMode {
+    ModeABC,
+    ModeABx,
+    ModeAsBx,
+    ModeAx,
+}
+
+/// Use of the B and C arguments of an opcode.
+#[deriving(Show, Eq, Clone)]
+pub enum ArgMode {
+    /// Unused.
+    ArgN,
+    /// Used as a plain value.
+    ArgU,
+    /// Register or jump offset.
+    ArgR,
+    /// Constant or register (RK).
+    ArgK,
+}
+
+/// Modes of every opcode, as (B, C, format) (see `luaP_opmodes` in `lopcodes.c`).
+static MODES: [(ArgMode, ArgMode, OpMode), ..40] = [
+    (ArgR, ArgN, ModeABC),  // MOVE
+    (ArgK, ArgN, ModeABx),  // LOADK
+    (ArgN, ArgN, ModeABx),  // LOADKX
+    (ArgU, ArgU, ModeABC),  // LOADBOOL
+    (ArgU, ArgN, ModeABC),  // LOADNIL
+    (ArgU, ArgN, ModeABC),  // GETUPVAL
+    (ArgU, ArgK, ModeABC),  // GETTABUP
+    (ArgR, ArgK, ModeABC),  // GETTABLE
+    (ArgK, ArgK, ModeABC),  // SETTABUP
+    (ArgU, ArgN, ModeABC),  // SETUPVAL
+    (ArgK, ArgK, ModeABC),  // SETTABLE
+    (ArgU, ArgU, ModeABC),  // NEWTABLE
+    (ArgR, ArgK, ModeABC),  // SELF
+    (ArgK, ArgK, ModeABC),  // ADD
+    (ArgK, ArgK, ModeABC),  // SUB
+    (ArgK, ArgK, ModeABC),  // MUL
+    (ArgK, ArgK, ModeABC),  // DIV
+    (ArgK, ArgK, ModeABC),  // MOD
+    (ArgK, ArgK, ModeABC),  // POW
+    (ArgR, ArgN, ModeABC),  // UNM
+    (ArgR, ArgN, ModeABC),  // NOT
+    (ArgR, ArgN, ModeABC),  // LEN
+    (ArgR, ArgR, ModeABC),  // CONCAT
+    (ArgR, ArgN, ModeAsBx), // JMP
+    (ArgK, ArgK, ModeABC),  // EQ
+    (ArgK, ArgK, ModeABC),  // LT
+    (ArgK, ArgK, ModeABC),  // LE
+    (ArgN, ArgU, ModeABC),  // TEST
+    (ArgR, ArgU, ModeABC),  // TESTSET
+    (ArgU, ArgU, ModeABC),  // CALL
+    (ArgU, ArgU, ModeABC),  // TAILCALL
+    (ArgU, ArgN, ModeABC),  // RETURN
+    (ArgR, ArgN, ModeAsBx), // FORLOOP
+    (ArgR, ArgN, ModeAsBx), // FORPREP
+    (ArgN, ArgU, ModeABC),  // TFORCALL
+    (ArgR, ArgN, ModeAsBx), // TFORLOOP
+    (ArgU, ArgU, ModeABC),  // SETLIST
+    (ArgU, ArgN, ModeABx),  // CLOSURE
+    (ArgU, ArgN, ModeABC),  // VARARG
+    (ArgU, ArgU, ModeAx),   // EXTRAARG
+];
+
+static NAMES: [&'static str, ..40] = [
+    "MOVE", "LOADK", "LOADKX", "LOADBOOL", "LOADNIL", "GETUPVAL", "GETTABUP", "GETTABLE",
+    "SETTABUP", "SETUPVAL", "SETTABLE", "NEWTABLE", "SELF", "ADD", "SUB", "MUL",
+    "DIV", "MOD", "POW", "UNM", "NOT", "LEN", "CONCAT", "JMP",
+    "EQ", "LT", "LE", "TEST", "TESTSET", "CALL", "TAILCALL", "RETURN",
+    "FORLOOP", "FORPREP", "TFORCALL", "TFORLOOP", "SETLIST", "CLOSURE", "VARARG", "EXTRAARG",
+];
+
+impl OpCode {
+    pub fn from_lua(op: uint) -> Option<OpCode> {
+        match op < OPCODES.len() {
+            true => Some(OPCODES[op]),
+            false => None,
+        }
+    }
+
+    /// Get the opcode name, as listed by `luac -l`.
+    pub fn name(&self) -> &'static str {
+        NAMES[*self as uint]
+    }
+
+    /// Get the instruction format.
+    pub fn mode(&self) -> OpMode {
+        let (_, _, mode) = MODES[*self as uint];
+        mode
+    }
+
+    /// Get the use of the B argument.
+    pub fn b_mode(&self) -> ArgMode {
+        let (b, _, _) = MODES[*self as uint];
+        b
+    }
+
+    /// Get the use of the C argument.
+    pub fn c_mode(&self) -> ArgMode {
+        let (_, c, _) = MODES[*self as uint];
+        c
+    }
+}
+
+/**
+ *  Virtual machine instruction (see `lopcodes.h`).
+ *
+ *  Instructions are 32 bits unsigned integers, with the opcode in the first 6 bits:
+ *
+ *  ```text
+ *  iABC:  B (9) | C (9) | A (8) | op (6)
+ *  iABx:     Bx (18)    | A (8) | op (6)
+ *  iAsBx:   sBx (18)    | A (8) | op (6)
+ *  iAx:          Ax (26)        | op (6)
+ *  ```
+ */
+#[deriving(Show, Eq, Clone)]
+pub struct Instruction(pub u32);
+
+impl Instruction {
+    /// Get the opcode, or None if the instruction is invalid.
+    pub fn opcode(&self) -> Option<OpCode> {
+        OpCode::from_lua(self.field(0, SIZE_OP))
+    }
+
+    pub fn a(&self) -> uint {
+        self.field(POS_A, SIZE_A)
+    }
+
+    pub fn b(&self) -> uint {
+        self.field(POS_B, SIZE_B)
+    }
+
+    pub fn c(&self) -> uint {
+        self.field(POS_C, SIZE_C)
+    }
+
+    pub fn bx(&self) -> uint {
+        self.field(POS_BX, SIZE_BX)
+    }
+
+    pub fn sbx(&self) -> int {
+        self.bx() as int - MAXARG_SBX
+    }
+
+    pub fn ax(&self) -> uint {
+        self.field(POS_AX, SIZE_AX)
+    }
+
+    fn field(&self, pos: uint, size: uint) -> uint {
+        let Instruction(i) = *self;
+        ((i >> pos) & ((1 << size) - 1)) as uint
+    }
+}
+
+/// Whether a RK argument (register or constant) refers to a constant.
+pub fn is_constant(rk: uint) -> bool {
+    rk & BITRK != 0
+}
+
+/// Get the constant index of a RK argument referring to a constant.
+pub fn constant_index(rk: uint) -> uint {
+    rk & !BITRK
+}
+
+#[cfg(test)]
+mod test {
+    use lua::Lua;
+    use bytecode::Chunk;
+    use status::LuaOk;
+
+    use super::{Instruction, OpSetTabUp, OpReturn};
+
+    #[test]
+    fn test_opcodes()
+    {
+        let lua = Lua::new();
+        assert_eq!(lua.state.load_str("x = 1"), LuaOk);
+        let chunk = Chunk::parse(lua.dump(false).unwrap().as_slice()).unwrap();
+        let ops: Vec<_> = chunk.main.code.iter().map(|&i| Instruction(i).opcode().unwrap()).collect();
+        assert_eq!(ops, vec![OpSetTabUp, OpReturn]);
+    }
+}
@@ -0,0 +1,405 @@
+use std::ptr;
+use std::str;
+
+use libc::c_int;
+
+use ffi;
+use state::State;
+use status::{LuaOk, LuaErr};
+use serial::{Key, NumberKey, StringKey, BoolKey, Tables, CycleError, DepthError};
+use serial::{format_number, array_len, table_keys, compare_keys};
+use types::*;
+
+static KEYWORDS: [&'static str, ..22] = [
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if",
+    "in", "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+pub struct PrettyOptions {
+    /// Indentation of nested tables, which are written on one line when None.
+    pub indent: Option<String>,
+    /// Whether to sort table keys: numbers, then strings, then booleans.
+    pub sort_keys: bool,
+    /**
+     *  Whether to fail on values which can't be written as source (functions, userdata,
+     *  threads and cycles), instead of writing them as nil followed by a comment.
+     *  Table fields whose keys can't be written are then skipped, and listed in a comment.
+     */
+    pub strict: bool,
+    /**
+     *  Whether to write functions, userdata and threads like `tostring` instead, when not strict,
+     *  e.g. to display values. The output can then no longer be loaded.
+     *  Errors raised by `__tostring` metamethods are caught and returned.
+     */
+    pub display: bool,
+}
+
+impl PrettyOptions {
+    /// Options writing everything on one line, failing on values which can't be written.
+    pub fn new() -> PrettyOptions {
+        PrettyOptions {
+            indent: None,
+            sort_keys: false,
+            strict: true,
+            display: false,
+        }
+    }
+}
+
+/**
+ *  Write the value at the given index as Lua source, e.g. to save it to a file loaded later.
+ *
+ *  Tables are written as constructors, their array part first. Tables appearing several times
+ *  are written each time, and cycles are detected.
+ *  Strings are quoted following the `%q` rules of `string.format`, except that bytes of
+ *  strings which aren't valid UTF-8 are escaped.
+ */
+pub fn to_source(state: &State, idx: int, options: &PrettyOptions) -> Result<String, String> {
+    let mut printer = Printer {
+        state: state,
+        options: options,
+        out: Vec::new(),
+        tables: Tables::new(),
+    };
+    try!(printer.value(state.abs_index(idx), 0));
+    // Every byte which isn't part of a valid UTF-8 string is escaped.
+    Ok(String::from_utf8(printer.out).unwrap())
+}
+
+struct Printer<'a, 'b> {
+    state: &'a State<'b>,
+    options: &'a PrettyOptions,
+    out: Vec<u8>,
+    /// Tables being written, to detect cycles.
+    tables: Tables,
+}
+
+impl<'a, 'b> Printer<'a, 'b> {
+    /// Write the value at the absolute index `idx`.
+    fn value(&mut self, idx: int, level: uint) -> Result<(), String> {
+        match self.state.get_type(idx) {
+            LuaNil => self.write("nil"),
+            LuaBoolean => match self.state.get_bool(idx) {
+                true => self.write("true"),
+                false => self.write("false"),
+            },
+            LuaNumber => {
+                let n = self.state.get_float(idx);
+                self.number(n);
+            }
+            LuaString => {
+                let s = self.state.get_bytes(idx);
+                self.string(s.as_slice());
+            }
+            LuaTable => return self.table(idx, level),
+            _ if self.options.display && !self.options.strict => return self.display(idx),
+            ty => {
+                let desc = describe(self.state, idx, ty);
+                return self.unrepresentable(desc.as_slice());
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the value at `idx` like `tostring`, calling its `__tostring` metamethod in protected mode.
+    fn display(&mut self, idx: int) -> Result<(), String> {
+        let state = self.state;
+        if !state.check_stack(2) {
+            return Err("stack overflow".to_string());
+        }
+        state.push_cfunction(_lua_tolstring);
+        state.push_value(idx);
+        let result = match state.pcall(1, 1, 0) {
+            LuaOk => {
+                let s = state.get_bytes(-1);
+                self.out.push_all(s.as_slice());
+                Ok(())
+            }
+            LuaErr(_) => match state.get_type(-1) {
+                LuaString | LuaNumber => Err(state.get_str(-1)),
+                _ => Err("(error object is not a string)".to_string()),
+            },
+        };
+        state.pop(1);
+        result
+    }
+
+    fn table(&mut self, idx: int, level: uint) -> Result<(), String> {
+        match self.tables.enter(self.state, idx, level) {
+            Ok(()) => {}
+            Err(CycleError) => return self.unrepresentable("cycle"),
+            Err(DepthError) => return Err("table too deeply nested".to_string()),
+        }
+        let result = self.table_content(idx, level);
+        self.tables.leave(self.state, idx);
+        result
+    }
+
+    fn table_content(&mut self, idx: int, level: uint) -> Result<(), String> {
+        let state = self.state;
+        let strict = self.options.strict;
+
+        // Array part, written without keys.
+        let len = array_len(state, idx);
+
+        let mut skipped = Vec::new();
+        let mut keys = try!(table_keys(state, idx, len, |ty| {
+            if strict {
+                return Err(format!("cannot write table key of type {} as source", ty));
+            }
+            skipped.push(describe(state, -1, ty));
+            Ok(())
+        }));
+        if self.options.sort_keys {
+            keys.sort_by(compare_keys);
+        }
+
+        self.write("{");
+        let mut first = true;
+
+        for i in range(1, len + 1) {
+            self.separator(&mut first, level + 1);
+            state.raw_get_index(idx, i);
+            let result = self.value(state.get_top(), level + 1);
+            state.pop(1);
+            try!(result);
+        }
+
+        for key in keys.iter() {
+            self.separator(&mut first, level + 1);
+            match *key {
+                StringKey(ref name) if is_name(name.as_slice()) => {
+                    self.out.push_all(name.as_slice());
+                }
+                _ => {
+                    self.write("[");
+                    self.key(key);
+                    self.write("]");
+                }
+            }
+            self.write(" = ");
+
+            key.push(state);
+            state.raw_get(idx);
+            let result = self.value(state.get_top(), level + 1);
+            state.pop(1);
+            try!(result);
+        }
+
+        // After the last field, where it can't be followed by a comma.
+        if !skipped.is_empty() {
+            if !first {
+                self.write(" ");
+            }
+            self.write(format!("--[[skipped keys: {}]]", skipped.connect(", ")).as_slice());
+        }
+
+        if !first {
+            self.newline(level);
+        }
+        self.write("}");
+        Ok(())
+    }
+
+    fn key(&mut self, key: &Key) {
+        match *key {
+            NumberKey(n) => self.number(n),
+            StringKey(ref s) => self.string(s.as_slice()),
+            BoolKey(b) => self.write(b.to_string().as_slice()),
+        }
+    }
+
+    /// Write what's needed before a table field.
+    fn separator(&mut self, first: &mut bool, level: uint) {
+        if !*first {
+            self.write(",");
+            if self.options.indent.is_none() {
+                self.write(" ");
+            }
+        }
+        *first = false;
+        self.newline(level);
+    }
+
+    /// Start a new line indented for `level`, when indenting.
+    fn newline(&mut self, level: uint) {
+        match self.options.indent {
+            Some(ref indent) => {
+                self.out.push('\n' as u8);
+                for _ in range(0, level) {
+                    self.out.push_all(indent.as_bytes());
+                }
+            }
+            None => {}
+        }
+    }
+
+    /// Fail, or write nil with a comment describing the value when not strict.
+    fn unrepresentable(&mut self, desc: &str) -> Result<(), String> {
+        if self.options.strict {
+            return Err(format!("cannot write {} as source", desc));
+        }
+        self.write(format!("nil --[[{}]]", desc).as_slice());
+        Ok(())
+    }
+
+    fn number(&mut self, n: f64) {
+        if n.is_nan() {
+            self.write("0/0");
+        } else if n.is_infinite() {
+            self.write(if n > 0.0 { "math.huge" } else { "-math.huge" });
+        } else {
+            self.write(format_number(n).as_slice());
+        }
+    }
+
+    /// Write a quoted string, like the `%q` format of `string.format` (see `addquoted` in `lstrlib.c`).
+    fn string(&mut self, s: &[u8]) {
+        let utf8 = str::from_utf8(s).is_some();
+
+        self.out.push('"' as u8);
+        for (i, &c) in s.iter().enumerate() {
+            let escape = match c as char {
+                '"' | '\\' | '\n' => {
+                    self.out.push('\\' as u8);
+                    false
+                }
+                '\x00'..'\x1f' | '\x7f' => true,
+                _ => c >= 0x80 && !utf8,
+            };
+
+            if escape {
+                // Use 3 digits when followed by a digit, which would be read as part of the escape.
+                let next_is_digit = i + 1 < s.len() && (s[i + 1] as char).is_digit();
+                let escaped = match next_is_digit {
+                    true => format!("\\{:03}", c),
+                    false => format!("\\{}", c),
+                };
+                self.out.push_all(escaped.as_bytes());
+            } else {
+                self.out.push(c);
+            }
+        }
+        self.out.push('"' as u8);
+    }
+
+    fn write(&mut self, s: &str) {
+        self.out.push_all(s.as_bytes());
+    }
+}
+
+/// Convert the first argument like `tostring`.
+extern "C" fn _lua_tolstring(raw: *ffi::lua_State) -> c_int {
+    unsafe {
+        ffi::luaL_tolstring(raw, 1, ptr::null());
+    }
+    1
+}
+
+/// Describe a value which can't be written as source, e.g. `function: 0x5581a2b0`.
+fn describe(state: &State, idx: int, ty: LuaType) -> String {
+    let name = match ty {
+        LuaFunction => "function",
+        LuaThread => "thread",
+        _ => "userdata",
+    };
+    format!("{}: {}", name, state.to_pointer(idx))
+}
+
+/// Whether a string is a Lua name, usable as table key without brackets.
+fn is_name(s: &[u8]) -> bool {
+    let name = match str::from_utf8(s) {
+        Some(name) => name,
+        None => return false,
+    };
+
+    let mut chars = name.chars();
+    let valid = match chars.next() {
+        Some(c) => (c.is_alphabetic() && c.is_ascii()) || c == '_',
+        None => false,
+    };
+    valid && chars.all(|c| (c.is_alphanumeric() && c.is_ascii()) || c == '_')
+        && !KEYWORDS.iter().any(|keyword| *keyword == name)
+}
+
+#[cfg(test)]
+mod test {
+    use lua::Lua;
+    use status::LuaOk;
+
+    use super::{PrettyOptions, to_source};
+
+    #[test]
+    fn test_pretty()
+    {
+        let lua = Lua::new();
+
+        assert_eq!(lua.exec_str(r#"
+            value = {1, 'a"\n\0' .. '1', [10] = 0.5, ['end'] = true, x = {y = {}}}
+            cycle = {}
+            cycle.self = cycle
+        "#), LuaOk);
+
+        let mut options = PrettyOptions::new();
+        options.sort_keys = true;
+
+        lua.state.get_global("value");
+        assert_eq!(to_source(&lua.state, -1, &options),
+                   Ok("{1, \"a\\\"\\\n\\0001\", [10] = 0.5, [\"end\"] = true, x = {y = {}}}".to_string()));
+        options.indent = Some("  ".to_string());
+        assert_eq!(to_source(&lua.state, -1, &options),
+                   Ok("{\n  1,\n  \"a\\\"\\\n\\0001\",\n  [10] = 0.5,\n  [\"end\"] = true,\n  x = {\n    y = {}\n  }\n}".to_string()));
+        lua.state.pop(1);
+
+        lua.state.get_global("cycle");
+        assert!(to_source(&lua.state, -1, &options).is_err());
+        options.strict = false;
+        options.indent = None;
+        assert_eq!(to_source(&lua.state, -1, &options), Ok("{self = nil --[[cycle]]}".to_string()));
+        lua.state.pop(1);
+    }
+
+    #[test]
+    fn test_pretty_numbers()
+    {
+        let lua = Lua::new();
+        let options = PrettyOptions::new();
+
+        assert_eq!(lua.exec_str("value = {123456789012345, 0.1, 1 / 3, 2^53, 1e300, 1 / 0, 0 / 0}"), LuaOk);
+        lua.state.get_global("value");
+        assert_eq!(to_source(&lua.state, -1, &options),
+                   Ok("{123456789012345, 0.1, 0.3333333333333333, 9007199254740992, 1e+300, math.huge, 0/0}".to_string()));
+        lua.state.pop(1);
+    }
+
+    #[test]
+    fn test_pretty_non_strict()
+    {
+        let lua = Lua::new();
+        lua.state.load_stdlibs();
+        let mut options = PrettyOptions::new();
+
+        assert_eq!(lua.exec_str("value = {a = 1, [print] = 2}"), LuaOk);
+        lua.state.get_global("value");
+        assert!(to_source(&lua.state, -1, &options).is_err());
+        options.strict = false;
+        let source = to_source(&lua.state, -1, &options).unwrap();
+        assert!(source.as_slice().starts_with("{a = 1 --[[skipped keys: function: "));
+        assert!(source.as_slice().ends_with("]]}"));
+        lua.state.pop(1);
+
+        assert_eq!(lua.exec_str("value = {f = print}"), LuaOk);
+        lua.state.get_global("value");
+        let source = to_source(&lua.state, -1, &options).unwrap();
+        assert!(source.as_slice().starts_with("{f = nil --[[function: "));
+        options.display = true;
+        let source = to_source(&lua.state, -1, &options).unwrap();
+        assert!(source.as_slice().starts_with("{f = function: "));
+
+        // Errors of `__tostring` are returned.
+        assert_eq!(lua.exec_str("debug.setmetatable(print, {__tostring = function() error('no tostring', 0) end})"),
+                   LuaOk);
+        assert_eq!(to_source(&lua.state, -1, &options), Err("no tostring".to_string()));
+        lua.state.pop(1);
+    }
+}
@@ -0,0 +1,304 @@
+use std::cell::RefCell;
+use std::io::{IoResult, Writer};
+use collections::hashmap::{HashMap, HashSet};
+
+use time;
+
+use lua::Lua;
+use debug::{
+    ActivationRecord,
+    DebugEvent,
+    DebugInfo,
+    HookMask,
+    HookCall,
+    HookReturn,
+    HookTailCall,
+    HookCount,
+    MASK_CALL,
+    MASK_RET,
+    MASK_COUNT,
+};
+
+/// How the profiler collects data.
+#[deriving(Clone)]
+pub enum ProfileMode {
+    /// Record the time spent in every function, using call and return hooks.
+    Tracing,
+    /// Record the current call stack every `count` instructions.
+    Sampling(int),
+}
+
+/// Statistics about a single function.
+#[deriving(Show, Clone)]
+pub struct FunctionStats {
+    pub calls: uint,
+    /// Time spent in the function and its callees, in nanoseconds (samples when sampling).
+    pub total: u64,
+    /// Time spent in the function itself, in nanoseconds (samples when sampling).
+    pub own: u64,
+}
+
+struct Frame {
+    label: String,
+    start: u64,
+    children: u64,
+}
+
+/**
+ *  Function-level profiler, built on debug hooks.
+ *
+ *  Results can be written as folded stacks (one `root;caller;callee weight` line per stack),
+ *  which is the input format of flamegraph tools.
+ */
+pub struct Profiler {
+    mode: ProfileMode,
+    data: RefCell<ProfileData>,
+}
+
+struct ProfileData {
+    stack: Vec<Frame>,
+    folded: HashMap<String, u64>,
+    functions: HashMap<String, FunctionStats>,
+    /// Number of activations of every function on the stack, to count recursive calls once in totals.
+    active: HashMap<String, uint>,
+}
+
+impl Profiler {
+    pub fn new(mode: ProfileMode) -> Profiler {
+        Profiler {
+            mode: mode,
+            data: RefCell::new(ProfileData {
+                stack: Vec::new(),
+                folded: HashMap::new(),
+                functions: HashMap::new(),
+                active: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Hook mask and count needed by this profiler.
+    pub fn hook_mask(&self) -> (HookMask, int) {
+        match self.mode {
+            Tracing => (MASK_CALL | MASK_RET, 0),
+            Sampling(count) => (MASK_COUNT, count),
+        }
+    }
+
+    /// Handle a debug hook event.
+    pub fn on_event(&self, lua: &Lua, event: DebugEvent, ar: &mut ActivationRecord) {
+        let mut data = self.data.borrow_mut();
+        match event {
+            HookCall => {
+                let info = ar.info(&lua.state);
+                data.enter(label(&info));
+            }
+            HookTailCall => {
+                // The caller frame is replaced and will not get a return event.
+                let info = ar.info(&lua.state);
+                data.leave();
+                data.enter(label(&info));
+            }
+            HookReturn => data.leave(),
+            HookCount => data.sample(lua),
+            _ => {}
+        }
+    }
+
+    /// Get the statistics of every profiled function.
+    pub fn functions(&self) -> HashMap<String, FunctionStats> {
+        self.data.borrow().functions.clone()
+    }
+
+    /**
+     *  Write the collected stacks in folded format.
+     *
+     *  Weights are microseconds when tracing, and samples when sampling.
+     */
+    pub fn write_folded<W: Writer>(&self, w: &mut W) -> IoResult<()> {
+        let data = self.data.borrow();
+        let mut stacks: Vec<(&String, &u64)> = data.folded.iter().collect();
+        stacks.sort();
+
+        for &(stack, &weight) in stacks.iter() {
+            let weight = match self.mode {
+                Tracing => weight / 1000,
+                Sampling(_) => weight,
+            };
+            if weight > 0 {
+                try!(writeln!(w, "{} {}", stack, weight));
+            }
+        }
+        Ok(())
+    }
+
+    /// Write a summary table, sorted by own time.
+    pub fn write_summary<W: Writer>(&self, w: &mut W) -> IoResult<()> {
+        let data = self.data.borrow();
+        let mut functions: Vec<(&String, &FunctionStats)> = data.functions.iter().collect();
+        functions.sort_by(|&(_, a), &(_, b)| b.own.cmp(&a.own));
+
+        match self.mode {
+            Tracing => {
+                try!(writeln!(w, "{:>10} {:>12} {:>12}  {}", "calls", "total (ms)", "own (ms)", "function"));
+                for &(label, stats) in functions.iter() {
+                    try!(writeln!(w, "{:>10} {:>12.3f} {:>12.3f}  {}", stats.calls,
+                                  stats.total as f64 / 1e6, stats.own as f64 / 1e6, label));
+                }
+            }
+            Sampling(_) => {
+                try!(writeln!(w, "{:>10} {:>10}  {}", "total", "own", "function"));
+                for &(label, stats) in functions.iter() {
+                    try!(writeln!(w, "{:>10} {:>10}  {}", stats.total, stats.own, label));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ProfileData {
+    fn enter(&mut self, label: String) {
+        self.functions.find_or_insert(label.clone(), FunctionStats {
+            calls: 0,
+            total: 0,
+            own: 0,
+        }).calls += 1;
+        *self.active.find_or_insert(label.clone(), 0) += 1;
+
+        self.stack.push(Frame {
+            label: label,
+            start: time::precise_time_ns(),
+            children: 0,
+        });
+    }
+
+    fn leave(&mut self) {
+        let stack = self.stack.iter().map(|frame| frame.label.as_slice()).collect::<Vec<&str>>().connect(";");
+        let frame = match self.stack.pop() {
+            Some(frame) => frame,
+            // Returning from a function called before the profiler was set.
+            None => return,
+        };
+
+        let total = time::precise_time_ns() - frame.start;
+        let own = total - frame.children;
+
+        *self.folded.find_or_insert(stack, 0) += own;
+
+        // The time of recursive calls is already part of the outermost call.
+        let outermost = match self.active.find_mut(&frame.label) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => true,
+        };
+        match self.functions.find_mut(&frame.label) {
+            Some(stats) => {
+                if outermost {
+                    stats.total += total;
+                }
+                stats.own += own;
+            }
+            None => {}
+        }
+
+        match self.stack.mut_last() {
+            Some(parent) => parent.children += total,
+            None => {}
+        }
+    }
+
+    fn sample(&mut self, lua: &Lua) {
+        let mut labels: Vec<String> = lua.backtrace().iter().map(label).collect();
+        labels.reverse();
+
+        // Recursive functions appear several times in a stack, but are counted once.
+        let mut seen = HashSet::new();
+        for (i, l) in labels.iter().enumerate() {
+            let stats = self.functions.find_or_insert(l.clone(), FunctionStats {
+                calls: 0,
+                total: 0,
+                own: 0,
+            });
+            if seen.insert(l.as_slice()) {
+                stats.total += 1;
+            }
+            if i == labels.len() - 1 {
+                stats.own += 1;
+            }
+        }
+
+        *self.folded.find_or_insert(labels.connect(";"), 0) += 1;
+    }
+}
+
+fn label(info: &DebugInfo) -> String {
+    let name = match info.name {
+        Some(ref name) => name.clone(),
+        None if info.what.as_slice() == "main" => "main chunk".to_string(),
+        None => "?".to_string(),
+    };
+
+    match info.what.as_slice() {
+        "C" => format!("{} [C]", name),
+        _ => format!("{} ({}:{})", name, info.short_src, info.line_defined),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use lua::Lua;
+
+    use super::{Profiler, ProfileMode, FunctionStats, Tracing, Sampling};
+
+    static RECURSIVE: &'static str = "
+        local function f(n)
+            if n == 0 then
+                local x = 0
+                for i = 1, 100000 do x = x + i end
+                return x
+            end
+            return f(n - 1) + 1
+        end
+        f(20)
+    ";
+
+    fn profile(mode: ProfileMode) -> Profiler {
+        let profiler = Profiler::new(mode);
+        {
+            let lua = Lua::new();
+            lua.set_profiler(&profiler);
+            assert_eq!(lua.exec_str(RECURSIVE), ::status::LuaOk);
+            lua.remove_hook();
+        }
+        profiler
+    }
+
+    fn stats(profiler: &Profiler, prefix: &str) -> FunctionStats {
+        let functions = profiler.functions();
+        let found = functions.iter().find(|&(label, _)| label.as_slice().starts_with(prefix));
+        found.map(|(_, stats)| stats.clone()).unwrap()
+    }
+
+    #[test]
+    fn test_tracing_recursion()
+    {
+        let profiler = profile(Tracing);
+        let f = stats(&profiler, "f ");
+        let main = stats(&profiler, "main chunk");
+        assert_eq!(f.calls, 21);
+        assert!(f.own <= f.total);
+        assert!(f.total <= main.total);
+    }
+
+    #[test]
+    fn test_sampling_recursion()
+    {
+        let profiler = profile(Sampling(100));
+        let f = stats(&profiler, "f ");
+        let main = stats(&profiler, "main chunk");
+        assert!(main.total > 0);
+        assert!(f.total <= main.total);
+    }
+}
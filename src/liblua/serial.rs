@@ -0,0 +1,224 @@
+// Helpers shared by the serializers of Lua values, `json` and `pretty`.
+
+use std::ptr;
+use std::str::raw;
+use std::cmp::{Ordering, Less, Equal, Greater};
+use collections::hashmap::HashSet;
+
+use libc::{c_char, c_double, c_int, c_void, size_t};
+
+use state::State;
+use types::*;
+
+/// Maximum nesting of tables.
+pub static MAX_DEPTH: uint = 1000;
+
+extern {
+    fn snprintf(s: *mut c_char, n: size_t, format: *c_char, ...) -> c_int;
+    fn strtod(s: *c_char, endp: *mut *c_char) -> c_double;
+}
+
+/// Table key which can be serialized.
+pub enum Key {
+    NumberKey(f64),
+    StringKey(Vec<u8>),
+    BoolKey(bool),
+}
+
+impl Key {
+    /// Push the key onto the stack.
+    pub fn push(&self, state: &State) {
+        match *self {
+            NumberKey(n) => state.push_float(n),
+            StringKey(ref s) => state.push_bytes(s.as_slice()),
+            BoolKey(b) => state.push_bool(b),
+        }
+    }
+}
+
+pub enum TableError {
+    /// The table is nested in itself.
+    CycleError,
+    /// Tables are nested deeper than `MAX_DEPTH`, or the stack can't grow.
+    DepthError,
+}
+
+/// Tables being serialized, to detect cycles.
+pub struct Tables {
+    visiting: HashSet<*c_void>,
+}
+
+impl Tables {
+    pub fn new() -> Tables {
+        Tables {
+            visiting: HashSet::new(),
+        }
+    }
+
+    /// Start serializing the table at `idx`, nested in `level` tables.
+    pub fn enter(&mut self, state: &State, idx: int, level: uint) -> Result<(), TableError> {
+        let ptr = state.to_pointer(idx);
+        if self.visiting.contains(&ptr) {
+            return Err(CycleError);
+        }
+        if level >= MAX_DEPTH || !state.check_stack(3) {
+            return Err(DepthError);
+        }
+        self.visiting.insert(ptr);
+        Ok(())
+    }
+
+    /// Finish serializing the table at `idx`.
+    pub fn leave(&mut self, state: &State, idx: int) {
+        self.visiting.remove(&state.to_pointer(idx));
+    }
+}
+
+/// Length of the sequence `1..n` of the table at `idx`, which ends before the first nil value.
+pub fn array_len(state: &State, idx: int) -> int {
+    let mut len = 0;
+    loop {
+        state.raw_get_index(idx, len + 1);
+        let end = match state.get_type(-1) {
+            LuaNil => true,
+            _ => false,
+        };
+        state.pop(1);
+        if end {
+            return len;
+        }
+        len += 1;
+    }
+}
+
+/**
+ *  Collect the keys of the table at the absolute index `idx`, except the integers `1..skip_len`.
+ *
+ *  `other` is called with keys of other types at the top of the stack, and fails to stop.
+ */
+pub fn table_keys(state: &State, idx: int, skip_len: int,
+                  other: |LuaType| -> Result<(), String>) -> Result<Vec<Key>, String> {
+    let mut keys = Vec::new();
+    state.push_nil();
+    while state.next(idx) {
+        state.pop(1);
+        let key = match state.get_type(-1) {
+            LuaNumber => {
+                let n = state.get_float(-1);
+                if n >= 1.0 && n <= skip_len as f64 && n == n.floor() {
+                    continue;
+                }
+                NumberKey(n)
+            }
+            LuaString => StringKey(state.get_bytes(-1)),
+            LuaBoolean => BoolKey(state.get_bool(-1)),
+            ty => match other(ty) {
+                Ok(()) => continue,
+                Err(msg) => {
+                    state.pop(1);
+                    return Err(msg);
+                }
+            },
+        };
+        keys.push(key);
+    }
+    Ok(keys)
+}
+
+/// Order keys by type (numbers, strings, then booleans), then by value.
+pub fn compare_keys(a: &Key, b: &Key) -> Ordering {
+    fn rank(key: &Key) -> uint {
+        match *key {
+            NumberKey(_) => 0,
+            StringKey(_) => 1,
+            BoolKey(_) => 2,
+        }
+    }
+
+    match (a, b) {
+        (&NumberKey(x), &NumberKey(y)) => {
+            if x < y { Less } else if x > y { Greater } else { Equal }
+        }
+        (&StringKey(ref x), &StringKey(ref y)) => x.cmp(y),
+        (&BoolKey(x), &BoolKey(y)) => x.cmp(&y),
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+/// Largest integer below which every integer is exactly representable as a double.
+static MAX_EXACT_INTEGER: f64 = 9007199254740992.0;
+
+/**
+ *  Format a finite number so that reading it back gives the same value.
+ *
+ *  Integers exactly representable as doubles are written without exponent nor decimal point,
+ *  other numbers with the fewest significant digits (at most 17) reading back to the same value.
+ *  `tostring` uses 14 digits, which loses precision.
+ */
+pub fn format_number(n: f64) -> String {
+    if n == n.floor() && n.abs() < MAX_EXACT_INTEGER {
+        // Keep the sign of negative zero.
+        if n == 0.0 && 1.0 / n < 0.0 {
+            return "-0".to_string();
+        }
+        return (n as i64).to_string();
+    }
+
+    let mut buf = [0 as c_char, ..32];
+    for precision in range(15, 17) {
+        let s = format_g(&mut buf, n, precision);
+        if parse_number(s.as_slice()) == Some(n) {
+            return s;
+        }
+    }
+    format_g(&mut buf, n, 17)
+}
+
+/// Parse a decimal number with `strtod`, which is correctly rounded, unlike `from_str`.
+pub fn parse_number(s: &str) -> Option<f64> {
+    s.with_c_str(|c_str| unsafe {
+        let mut end: *c_char = ptr::null();
+        let n = strtod(c_str, &mut end);
+        match end == c_str.offset(s.len() as int) && !s.is_empty() {
+            true => Some(n as f64),
+            false => None,
+        }
+    })
+}
+
+/// Format a number with `%.*g`.
+fn format_g(buf: &mut [c_char, ..32], n: f64, precision: int) -> String {
+    "%.*g".with_c_str(|format| unsafe {
+        snprintf(buf.as_mut_ptr(), buf.len() as size_t, format, precision as c_int, n as c_double);
+        raw::from_c_str(buf.as_ptr())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{format_number, parse_number};
+
+    #[test]
+    fn test_format_number()
+    {
+        assert_eq!(format_number(0.0).as_slice(), "0");
+        assert_eq!(format_number(-0.0).as_slice(), "-0");
+        assert_eq!(format_number(-12.0).as_slice(), "-12");
+        assert_eq!(format_number(123456789012345.0).as_slice(), "123456789012345");
+        assert_eq!(format_number(9007199254740991.0).as_slice(), "9007199254740991");
+        assert_eq!(format_number(0.5).as_slice(), "0.5");
+        assert_eq!(format_number(0.1).as_slice(), "0.1");
+        assert_eq!(format_number(1.0 / 3.0).as_slice(), "0.3333333333333333");
+        assert_eq!(format_number(1e100).as_slice(), "1e+100");
+    }
+
+    #[test]
+    fn test_parse_number()
+    {
+        assert_eq!(parse_number("0.1"), Some(0.1));
+        assert_eq!(parse_number("0.3333333333333333"), Some(1.0 / 3.0));
+        assert_eq!(parse_number("-1.5e3"), Some(-1500.0));
+        assert_eq!(parse_number("1x"), None);
+        assert_eq!(parse_number(""), None);
+    }
+}
@@ -1,11 +1,21 @@
+use std::io::{IoError, IoResult, InvalidInput, Reader, Writer};
 use std::mem::transmute;
+use std::ptr::null;
 use std::str::raw;
+use std::vec;
 
-use libc::c_int;
+use libc;
+use libc::{c_char, c_int, c_void, size_t};
 
 use ffi;
+use from_c_str;
+use debug::{ActivationRecord, HookMask};
+use load::{LoadMode, ReaderData, _lua_reader};
+use dump::{WriterData, _lua_writer};
 use types::LuaType;
-use status::LuaStatus;
+use status::{LuaStatus, LuaOk, LuaErr, FileError};
+use stdlib;
+use stdlib::StdLib;
 use lua::Lua;
 
 /**
@@ -51,6 +61,11 @@ impl<'a> State<'a> {
         }
     }
 
+    /// Get the raw Lua state pointer.
+    pub unsafe fn as_ffi(&self) -> *ffi::lua_State {
+        self.raw
+    }
+
     /**
      *  Close the Lua state.
      *
@@ -67,6 +82,66 @@ impl<'a> State<'a> {
         }
     }
 
+    /// Load a selection of Lua standard libraries, setting them as globals.
+    pub fn open_libs(&self, libs: StdLib) {
+        for &(name, f) in libs.libs().iter() {
+            name.with_c_str(|name| unsafe {
+                ffi::luaL_requiref(self.raw, name, f, 1);
+            });
+            self.pop(1);
+        }
+    }
+
+    /**
+     *  Load the safe preset of Lua standard libraries.
+     *
+     *  `dofile` and `loadfile` are removed, `os` only keeps its time functions, and `load` only
+     *  accepts text chunks.
+     */
+    pub fn load_safe_stdlibs(&self) {
+        self.open_libs(stdlib::SAFE);
+
+        for &(lib, name) in stdlib::UNSAFE_FUNCTIONS.iter() {
+            self.get_global(lib);
+            self.push_nil();
+            self.set_field(-2, name);
+            self.pop(1);
+        }
+
+        // Remove every other function of `os`, in place as it's also in `package.loaded`.
+        self.get_global(ffi::LUA_OSLIBNAME);
+        let mut names = Vec::new();
+        self.push_nil();
+        while self.next(-2) {
+            self.pop(1);
+            let name = self.get_str(-1);
+            if !stdlib::SAFE_OS_FUNCTIONS.iter().any(|safe| *safe == name.as_slice()) {
+                names.push(name);
+            }
+        }
+        for name in names.iter() {
+            self.push_nil();
+            self.set_field(-2, name.as_slice());
+        }
+        self.pop(1);
+
+        let status = match self.load_str(stdlib::SAFE_LOAD) {
+            LuaOk => self.exec(),
+            status => status,
+        };
+        match status {
+            LuaOk => {}
+            _ => {
+                let msg = self.to_display_str(-1);
+                self.pop(1);
+                // Never leave the unrestricted `load` in place.
+                self.push_nil();
+                self.set_global("load");
+                fail!("Can't restrict `load` to text chunks: {}", msg);
+            }
+        }
+    }
+
     /// Get type of a stack element at specified index.
     pub fn get_type(&self, idx: int) -> LuaType {
         let ty = unsafe {
@@ -132,6 +207,17 @@ impl<'a> State<'a> {
         LuaStatus::from_lua(status)
     }
 
+    /// Load a file, only accepting chunks allowed by `mode`.
+    pub fn load_filex(&self, filename: &str, mode: LoadMode) -> LuaStatus {
+        let status = filename.with_c_str(|filename| {
+            mode.to_lua().with_c_str(|mode| unsafe {
+                ffi::luaL_loadfilex(self.raw, filename, mode)
+            })
+        });
+
+        LuaStatus::from_lua(status)
+    }
+
     pub fn load_str(&self, source: &str) -> LuaStatus {
         let status = source.with_c_str(|source| unsafe {
             ffi::luaL_loadstring(self.raw, source)
@@ -140,6 +226,77 @@ impl<'a> State<'a> {
         LuaStatus::from_lua(status)
     }
 
+    /// Load a string, only accepting chunks allowed by `mode`.
+    pub fn load_strx(&self, source: &str, mode: LoadMode) -> LuaStatus {
+        let status = source.with_c_str(|source| {
+            mode.to_lua().with_c_str(|mode| unsafe {
+                ffi::luaL_loadbufferx(self.raw, source, libc::strlen(source), source, mode)
+            })
+        });
+
+        LuaStatus::from_lua(status)
+    }
+
+    /**
+     *  Load a chunk from a buffer, named `chunkname` in error messages and debug information.
+     *
+     *  By convention, file chunk names start with `@` (e.g. `@plugins/foo.lua`).
+     */
+    pub fn load_buffer(&self, buf: &[u8], chunkname: &str, mode: LoadMode) -> LuaStatus {
+        let status = chunkname.with_c_str(|chunkname| {
+            mode.to_lua().with_c_str(|mode| unsafe {
+                ffi::luaL_loadbufferx(self.raw, buf.as_ptr() as *c_char, buf.len() as size_t, chunkname, mode)
+            })
+        });
+
+        LuaStatus::from_lua(status)
+    }
+
+    /**
+     *  Load a chunk by streaming it from a reader, named `chunkname` in error messages.
+     *
+     *  Read errors are reported as `FileError`, with the error message pushed to the stack.
+     */
+    pub fn load_reader(&self, reader: &mut Reader, chunkname: &str, mode: LoadMode) -> LuaStatus {
+        let mut data = ReaderData::new(reader);
+        let status = chunkname.with_c_str(|chunkname| {
+            mode.to_lua().with_c_str(|mode| unsafe {
+                ffi::lua_load(self.raw, _lua_reader, transmute(&mut data), chunkname, mode)
+            })
+        });
+
+        match data.error {
+            Some(e) => {
+                self.pop(1);
+                self.push_str(format!("cannot read {}: {}", chunkname, e).as_slice());
+                LuaErr(FileError)
+            }
+            None => LuaStatus::from_lua(status),
+        }
+    }
+
+    /**
+     *  Dump the Lua function at the top of the stack as a precompiled chunk.
+     *
+     *  The function is not popped. Fail if the value isn't a Lua function.
+     */
+    pub fn dump(&self, writer: &mut Writer) -> IoResult<()> {
+        let mut data = WriterData::new(writer);
+        let status = unsafe {
+            ffi::lua_dump(self.raw, _lua_writer, transmute(&mut data))
+        };
+
+        match (data.error, status) {
+            (Some(e), _) => Err(e),
+            (None, 0) => Ok(()),
+            (None, _) => Err(IoError {
+                kind: InvalidInput,
+                desc: "unable to dump given function",
+                detail: None,
+            }),
+        }
+    }
+
     pub fn pcall(&self, nargs: int, nresults: int, errfunc: int) -> LuaStatus {
         let status = unsafe {
             ffi::lua_pcall(self.raw, nargs as c_int, nresults as c_int, errfunc as c_int)
@@ -173,6 +330,34 @@ impl<'a> State<'a> {
         }
     }
 
+    /// Ensure there's room for `extra` more elements on the stack.
+    pub fn check_stack(&self, extra: int) -> bool {
+        unsafe {
+            ffi::lua_checkstack(self.raw, extra as c_int) != 0
+        }
+    }
+
+    /// Convert the acceptable index `idx` into an absolute index.
+    pub fn abs_index(&self, idx: int) -> int {
+        unsafe {
+            ffi::lua_absindex(self.raw, idx as c_int) as int
+        }
+    }
+
+    /// Push a copy of the element at the given index.
+    pub fn push_value(&self, idx: int) {
+        unsafe {
+            ffi::lua_pushvalue(self.raw, idx as c_int);
+        }
+    }
+
+    /// Move the top element into the given valid index, shifting up the elements above it.
+    pub fn insert(&self, idx: int) {
+        unsafe {
+            ffi::lua_insert(self.raw, idx as c_int);
+        }
+    }
+
     /// Removes the element at the given valid index.
     pub fn remove(&self, idx: int) {
         unsafe {
@@ -199,18 +384,42 @@ impl<'a> State<'a> {
         }
     }
 
+    pub fn raw_get(&self, idx: int) {
+        unsafe {
+            ffi::lua_rawget(self.raw, idx as c_int);
+        }
+    }
+
     pub fn raw_set(&self, idx: int) {
         unsafe {
             ffi::lua_rawset(self.raw, idx as c_int);
         }
     }
 
+    pub fn raw_get_index(&self, idx: int, i: int) {
+        unsafe {
+            ffi::lua_rawgeti(self.raw, idx as c_int, i as c_int);
+        }
+    }
+
     pub fn raw_set_index(&self, idx: int, i: int) {
         unsafe {
             ffi::lua_rawseti(self.raw, idx as c_int, i as c_int);
         }
     }
 
+    pub fn raw_get_ptr<T>(&self, idx: int, p: *T) {
+        unsafe {
+            ffi::lua_rawgetp(self.raw, idx as c_int, transmute(p));
+        }
+    }
+
+    pub fn raw_set_ptr<T>(&self, idx: int, p: *T) {
+        unsafe {
+            ffi::lua_rawsetp(self.raw, idx as c_int, transmute(p));
+        }
+    }
+
     pub fn next(&self, idx: int) -> bool {
         unsafe {
             ffi::lua_next(self.raw, idx as c_int) != 0
@@ -228,6 +437,13 @@ impl<'a> State<'a> {
         len
     }
 
+    /// Get the length of the value at the given index, without metamethods.
+    pub fn raw_len(&self, idx: int) -> uint {
+        unsafe {
+            ffi::lua_rawlen(self.raw, idx as c_int) as uint
+        }
+    }
+
     pub fn get_metatable(&self, idx: int) -> bool {
         unsafe {
             ffi::lua_getmetatable(self.raw, idx as c_int) != 0
@@ -272,6 +488,20 @@ impl<'a> State<'a> {
         });
     }
 
+    /// Push a string which may contain any byte, including zeros.
+    pub fn push_bytes(&self, bytes: &[u8]) {
+        unsafe {
+            ffi::lua_pushlstring(self.raw, bytes.as_ptr() as *c_char, bytes.len() as size_t);
+        }
+    }
+
+    /// Push the global environment.
+    pub fn push_globals(&self) {
+        unsafe {
+            ffi::lua_pushglobaltable(self.raw);
+        }
+    }
+
     pub fn push_bool(&self, b: bool) {
         unsafe {
             let n = if b { 1 } else { 0 };
@@ -285,6 +515,13 @@ impl<'a> State<'a> {
         }
     }
 
+    /// Push a new full userdata of `size` bytes, and return its address.
+    pub fn new_userdata(&self, size: uint) -> *mut c_void {
+        unsafe {
+            ffi::lua_newuserdata(self.raw, size as size_t) as *mut c_void
+        }
+    }
+
     pub fn push_cclosure(&self, f: ffi::lua_CFunction, n: int) {
         unsafe {
             ffi::lua_pushcclosure(self.raw, f, n as c_int);
@@ -329,6 +566,35 @@ impl<'a> State<'a> {
         }
     }
 
+    /**
+     *  Convert any value at the given index to a string, like the `tostring` Lua function.
+     *
+     *  Use the `__tostring` metamethod if any.
+     */
+    pub fn to_display_str(&self, idx: int) -> String {
+        let s = unsafe {
+            raw::from_c_str(ffi::luaL_tolstring(self.raw, idx as c_int, transmute(null::<c_void>())))
+        };
+        self.pop(1);
+        s
+    }
+
+    /**
+     *  Get the content of a string, which may contain any byte, including zeros.
+     *
+     *  Numbers are converted in place, and other values give an empty vector.
+     */
+    pub fn get_bytes(&self, idx: int) -> Vec<u8> {
+        let mut len: size_t = 0;
+        unsafe {
+            let s = ffi::lua_tolstring(self.raw, idx as c_int, &mut len as *mut size_t as *size_t);
+            match s.is_null() {
+                true => Vec::new(),
+                false => vec::raw::from_buf(s as *u8, len as uint),
+            }
+        }
+    }
+
     pub fn get_bool(&self, idx: int) -> bool {
         unsafe {
             ffi::lua_toboolean(self.raw, idx as c_int) != 0
@@ -341,6 +607,20 @@ impl<'a> State<'a> {
         }
     }
 
+    /// Get a pointer identifying a table, function, thread or userdata, for debugging or hashing.
+    pub fn to_pointer(&self, idx: int) -> *c_void {
+        unsafe {
+            ffi::lua_topointer(self.raw, idx as c_int)
+        }
+    }
+
+    /// Whether the value at the given index is a C function.
+    pub fn is_cfunction(&self, idx: int) -> bool {
+        unsafe {
+            ffi::lua_iscfunction(self.raw, idx as c_int) != 0
+        }
+    }
+
     pub fn get_cfunction(&self, idx: int) -> ffi::lua_CFunction {
         unsafe {
             ffi::lua_tocfunction(self.raw, idx as c_int)
@@ -360,14 +640,182 @@ impl<'a> State<'a> {
             ffi::lua_error(self.raw) as int
         }
     }
+
+    /// Control the garbage collector, `what` being one of the `LUA_GC*` options.
+    pub fn gc(&self, what: int, data: int) -> int {
+        unsafe {
+            ffi::lua_gc(self.raw, what as c_int, data as c_int) as int
+        }
+    }
+
+    /**
+     *  Call the metamethod `event` of the value at the given index, pushing its result.
+     *
+     *  Return false, pushing nothing, if there's no such metamethod.
+     */
+    pub fn call_meta(&self, idx: int, event: &str) -> bool {
+        event.with_c_str(|event| unsafe {
+            ffi::luaL_callmeta(self.raw, idx as c_int, event) != 0
+        })
+    }
+
+    // Debug functions
+    /// Set the debug hook function.
+    pub fn set_hook(&self, f: ffi::lua_Hook, mask: HookMask, count: int) {
+        unsafe {
+            ffi::lua_sethook(self.raw, f, mask.to_lua(), count as c_int);
+        }
+    }
+
+    /// Remove the debug hook function.
+    pub fn remove_hook(&self) {
+        unsafe {
+            ffi::lua_sethook(self.raw, transmute(null::<c_void>()), 0, 0);
+        }
+    }
+
+    pub fn get_hook_mask(&self) -> HookMask {
+        let mask = unsafe {
+            ffi::lua_gethookmask(self.raw)
+        };
+        HookMask::from_lua(mask)
+    }
+
+    pub fn get_hook_count(&self) -> int {
+        unsafe {
+            ffi::lua_gethookcount(self.raw) as int
+        }
+    }
+
+    /**
+     *  Get the activation record of the function executing at the given level.
+     *
+     *  Level 0 is the current running function, level n+1 is the function that has called level n.
+     *  Return None when level is greater than the stack depth.
+     */
+    pub fn get_stack(&self, level: int) -> Option<ActivationRecord> {
+        let ar = ActivationRecord::new();
+        let found = unsafe {
+            ffi::lua_getstack(self.raw, level as c_int, ar.as_ptr()) != 0
+        };
+
+        match found {
+            true => Some(ar),
+            false => None,
+        }
+    }
+
+    /**
+     *  Push the value of the local variable `n` of the given activation record.
+     *
+     *  Return the variable name, or None (pushing nothing) if there is no such local.
+     */
+    pub fn get_local(&self, ar: &ActivationRecord, n: int) -> Option<String> {
+        unsafe {
+            from_c_str(ffi::lua_getlocal(self.raw, ar.as_ptr(), n as c_int))
+        }
+    }
+
+    /**
+     *  Pop a value and assign it to the local variable `n` of the given activation record.
+     *
+     *  Return the variable name, or None (popping nothing) if there is no such local.
+     */
+    pub fn set_local(&self, ar: &ActivationRecord, n: int) -> Option<String> {
+        unsafe {
+            from_c_str(ffi::lua_setlocal(self.raw, ar.as_ptr(), n as c_int))
+        }
+    }
+
+    /**
+     *  Push the value of the upvalue `n` of the closure at index `funcindex`.
+     *
+     *  Return the upvalue name (empty for C functions), or None (pushing nothing) if there is no such upvalue.
+     */
+    pub fn get_upvalue(&self, funcindex: int, n: int) -> Option<String> {
+        unsafe {
+            from_c_str(ffi::lua_getupvalue(self.raw, funcindex as c_int, n as c_int))
+        }
+    }
+
+    /**
+     *  Pop a value and assign it to the upvalue `n` of the closure at index `funcindex`.
+     *
+     *  Return the upvalue name, or None (popping nothing) if there is no such upvalue.
+     */
+    pub fn set_upvalue(&self, funcindex: int, n: int) -> Option<String> {
+        unsafe {
+            from_c_str(ffi::lua_setupvalue(self.raw, funcindex as c_int, n as c_int))
+        }
+    }
+
+    /**
+     *  Get an unique identifier for the upvalue `n` of the closure at index `funcindex`.
+     *
+     *  Return None if there is no such upvalue.
+     */
+    pub fn upvalue_id(&self, funcindex: int, n: int) -> Option<*c_void> {
+        if !self.has_upvalue(funcindex, n) {
+            return None;
+        }
+
+        unsafe {
+            Some(ffi::lua_upvalueid(self.raw, funcindex as c_int, n as c_int))
+        }
+    }
+
+    /**
+     *  Make the upvalue `n1` of the closure at `funcindex1` refer to the upvalue `n2` of the closure at `funcindex2`.
+     *
+     *  Both closures must be Lua functions. Return false, doing nothing, if an upvalue doesn't exist.
+     */
+    pub fn upvalue_join(&self, funcindex1: int, n1: int, funcindex2: int, n2: int) -> bool {
+        if self.is_cfunction(funcindex1) || self.is_cfunction(funcindex2)
+            || !self.has_upvalue(funcindex1, n1) || !self.has_upvalue(funcindex2, n2) {
+            return false;
+        }
+
+        unsafe {
+            ffi::lua_upvaluejoin(self.raw, funcindex1 as c_int, n1 as c_int, funcindex2 as c_int, n2 as c_int);
+        }
+        true
+    }
+
+    /// Whether the closure at index `funcindex` has an upvalue `n`.
+    fn has_upvalue(&self, funcindex: int, n: int) -> bool {
+        match self.get_upvalue(funcindex, n) {
+            Some(_) => {
+                self.pop(1);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Push a traceback of the stack starting at `level`, prefixed by `msg` if any.
+    pub fn traceback(&self, msg: Option<&str>, level: int) {
+        match msg {
+            Some(msg) => msg.with_c_str(|msg| unsafe {
+                ffi::luaL_traceback(self.raw, self.raw, msg, level as c_int);
+            }),
+            None => unsafe {
+                ffi::luaL_traceback(self.raw, self.raw, null(), level as c_int);
+            },
+        }
+    }
+
+    /// Fill the activation record fields selected by `what`.
+    pub fn get_info(&self, what: &str, ar: &mut ActivationRecord) -> bool {
+        what.with_c_str(|what| unsafe {
+            ffi::lua_getinfo(self.raw, what, ar.as_ptr()) != 0
+        })
+    }
 }
 
 extern "C" fn _lua_state_closure(raw: *ffi::lua_State) -> c_int {
-    let lua = Lua {
-        state: unsafe {
-            State::from_ffi(raw)
-        },
-    };
+    let lua = Lua::from_state(unsafe {
+        State::from_ffi(raw)
+    });
     let f: fn(l: &Lua) -> int = match lua.get_arg(0) {
         Some(p) => p,
         None => fail!("I'm sorry Dave I'm afraid I can't do that."),
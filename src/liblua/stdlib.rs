@@ -0,0 +1,99 @@
+use ffi;
+
+/// Set of Lua standard libraries.
+#[deriving(Clone, Eq)]
+pub struct StdLib {
+    bits: uint,
+}
+
+pub static BASE: StdLib = StdLib { bits: 1 << 0 };
+pub static PACKAGE: StdLib = StdLib { bits: 1 << 1 };
+pub static COROUTINE: StdLib = StdLib { bits: 1 << 2 };
+pub static TABLE: StdLib = StdLib { bits: 1 << 3 };
+pub static IO: StdLib = StdLib { bits: 1 << 4 };
+pub static OS: StdLib = StdLib { bits: 1 << 5 };
+pub static STRING: StdLib = StdLib { bits: 1 << 6 };
+pub static BIT32: StdLib = StdLib { bits: 1 << 7 };
+pub static MATH: StdLib = StdLib { bits: 1 << 8 };
+pub static DEBUG: StdLib = StdLib { bits: 1 << 9 };
+
+/// Every standard library, as loaded by `luaL_openlibs`.
+pub static ALL: StdLib = StdLib { bits: (1 << 10) - 1 };
+
+/**
+ *  Libraries loaded by the safe preset.
+ *
+ *  `package` is left out as it can load native code, `io` as it can read and write any file,
+ *  and `debug` as it can break any sandbox. `os` is restricted to `SAFE_OS_FUNCTIONS`.
+ */
+pub static SAFE: StdLib = StdLib {
+    bits: (1 << 0) | (1 << 2) | (1 << 3) | (1 << 5) | (1 << 6) | (1 << 7) | (1 << 8),
+};
+
+impl StdLib {
+    pub fn empty() -> StdLib {
+        StdLib { bits: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits == 0
+    }
+
+    pub fn contains(&self, other: StdLib) -> bool {
+        (self.bits & other.bits) == other.bits
+    }
+
+    /// Get the name and open function of every library of this set, in loading order.
+    pub fn libs(&self) -> Vec<(&'static str, ffi::lua_CFunction)> {
+        let libs: [(StdLib, &'static str, ffi::lua_CFunction), ..10] = [
+            (BASE, "_G", ffi::luaopen_base),
+            (PACKAGE, ffi::LUA_LOADLIBNAME, ffi::luaopen_package),
+            (COROUTINE, ffi::LUA_COLIBNAME, ffi::luaopen_coroutine),
+            (TABLE, ffi::LUA_TABLIBNAME, ffi::luaopen_table),
+            (IO, ffi::LUA_IOLIBNAME, ffi::luaopen_io),
+            (OS, ffi::LUA_OSLIBNAME, ffi::luaopen_os),
+            (STRING, ffi::LUA_STRLIBNAME, ffi::luaopen_string),
+            (BIT32, ffi::LUA_BITLIBNAME, ffi::luaopen_bit32),
+            (MATH, ffi::LUA_MATHLIBNAME, ffi::luaopen_math),
+            (DEBUG, ffi::LUA_DBLIBNAME, ffi::luaopen_debug),
+        ];
+
+        libs.iter()
+            .filter(|&&(lib, _, _)| self.contains(lib))
+            .map(|&(_, name, f)| (name, f))
+            .collect()
+    }
+}
+
+impl BitOr<StdLib, StdLib> for StdLib {
+    fn bitor(&self, rhs: &StdLib) -> StdLib {
+        StdLib { bits: self.bits | rhs.bits }
+    }
+}
+
+impl Sub<StdLib, StdLib> for StdLib {
+    fn sub(&self, rhs: &StdLib) -> StdLib {
+        StdLib { bits: self.bits & !rhs.bits }
+    }
+}
+
+/// Functions removed from the libraries of the safe preset.
+pub static UNSAFE_FUNCTIONS: &'static [(&'static str, &'static str)] = &[
+    ("_G", "dofile"),
+    ("_G", "loadfile"),
+];
+
+/// Functions of `os` kept by the safe preset, as they can't access files, processes or the environment.
+pub static SAFE_OS_FUNCTIONS: &'static [&'static str] = &[
+    "clock",
+    "date",
+    "difftime",
+    "time",
+];
+
+/// Replace `load` by a version which only accepts text chunks.
+pub static SAFE_LOAD: &'static str = "\
+local load = load
+_G.load = function(chunk, chunkname, mode, ...)
+    return load(chunk, chunkname, 't', ...)
+end";
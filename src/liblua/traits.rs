@@ -109,6 +109,25 @@ impl ToLua for bool {
     }
 }
 
+// Option
+impl<T: FromLua> FromLua for Option<T> {
+    fn from_lua(state: &State, idx: int) -> Option<Option<T>> {
+        match state.get_type(idx) {
+            LuaNone | LuaNil => Some(None),
+            _ => FromLua::from_lua(state, idx).map(|val| Some(val)),
+        }
+    }
+}
+
+impl<T: ToLua> ToLua for Option<T> {
+    fn to_lua(&self, state: &State) {
+        match *self {
+            Some(ref val) => val.to_lua(state),
+            None => state.push_nil(),
+        }
+    }
+}
+
 // Pointer
 impl<T> FromLua for *T {
     fn from_lua(state: &State, idx: int) -> Option<*T> {
@@ -0,0 +1,122 @@
+use collections::hashmap::HashMap;
+
+use libc::c_int;
+
+use ffi;
+use lua::Lua;
+use state::State;
+use status::LuaOk;
+
+/**
+ *  In-memory tree of Lua source files, usable by `require`.
+ *
+ *  Files are typically embedded in the binary with `include_bin!`:
+ *
+ *  ```ignore
+ *  let mut fs = EmbeddedFs::new("scripts");
+ *  fs.add("a/b.lua", include_bin!("scripts/a/b.lua"));
+ *  lua.add_embedded_fs(&fs);
+ *  ```
+ *
+ *  Then `require "a.b"` loads `a/b.lua` (or `a/b/init.lua`), with `@scripts/a/b.lua` as chunk name.
+ */
+pub struct EmbeddedFs<'a> {
+    root: String,
+    files: HashMap<String, &'a [u8]>,
+}
+
+impl<'a> EmbeddedFs<'a> {
+    /// Create an empty tree, whose files are named relatively to `root` in error messages.
+    pub fn new(root: &str) -> EmbeddedFs<'a> {
+        EmbeddedFs {
+            root: root.to_string(),
+            files: HashMap::new(),
+        }
+    }
+
+    /// Add a file, `path` being relative to the tree root and using `/` as separator.
+    pub fn add(&mut self, path: &str, source: &'a [u8]) {
+        self.files.insert(path.to_string(), source);
+    }
+
+    /// Get a file content by path.
+    pub fn get(&self, path: &str) -> Option<&'a [u8]> {
+        self.files.find_equiv(&path).map(|source| *source)
+    }
+
+    /// Get the candidate paths of a module name, in search order.
+    pub fn module_paths(name: &str) -> Vec<String> {
+        let path = name.replace(".", "/");
+        vec![format!("{}.lua", path), format!("{}/init.lua", path)]
+    }
+
+    /// Find the file of a module, returning its path and content.
+    pub fn find_module(&self, name: &str) -> Option<(String, &'a [u8])> {
+        for path in EmbeddedFs::module_paths(name).move_iter() {
+            match self.get(path.as_slice()) {
+                Some(source) => return Some((path, source)),
+                None => {}
+            }
+        }
+        None
+    }
+
+    /// Get the name of a file, as displayed in chunk names and error messages.
+    pub fn display_path(&self, path: &str) -> String {
+        match self.root.len() {
+            0 => path.to_string(),
+            _ => format!("{}/{}", self.root, path),
+        }
+    }
+}
+
+/**
+ *  `require` searcher looking into the `EmbeddedFs` given as first upvalue.
+ *
+ *  Behave like the standard Lua files searcher: return the loaded chunk and its filename,
+ *  or a message listing the tried files.
+ */
+pub extern "C" fn _lua_embedded_searcher(raw: *ffi::lua_State) -> c_int {
+    let lua = Lua::from_state(unsafe {
+        State::from_ffi(raw)
+    });
+
+    let result = {
+        let fs: *EmbeddedFs = match lua.get_arg(0) {
+            Some(p) => p,
+            None => fail!("Embedded searcher without file tree!"),
+        };
+        let fs = unsafe { &*fs };
+        let name: String = lua.get(1).unwrap_or(String::new());
+
+        match fs.find_module(name.as_slice()) {
+            Some((path, source)) => {
+                let filename = fs.display_path(path.as_slice());
+                match lua.state.load_buffer(source, format!("@{}", filename).as_slice(), lua.load_mode()) {
+                    LuaOk => {
+                        lua.push(filename);
+                        Ok(2)
+                    }
+                    _ => {
+                        let msg = lua.state.to_display_str(-1);
+                        lua.push(format!("error loading module '{}' from file '{}':\n\t{}", name, filename, msg));
+                        Err(())
+                    }
+                }
+            }
+            None => {
+                let mut msg = String::new();
+                for path in EmbeddedFs::module_paths(name.as_slice()).iter() {
+                    msg.push_str(format!("\n\tno embedded file '{}'", fs.display_path(path.as_slice())).as_slice());
+                }
+                lua.push(msg);
+                Ok(1)
+            }
+        }
+    };
+
+    match result {
+        Ok(n) => n as c_int,
+        Err(()) => lua.state.error() as c_int,
+    }
+}
@@ -0,0 +1,127 @@
+#![crate_id = "luatest#0.3.0"]
+#![crate_type = "rlib"]
+#![license = "MIT"]
+
+#![feature(phase)]
+
+// Tests of the exported macros, whose `::lua::` paths only resolve outside the `lua` crate.
+
+#[phase(syntax, link)]
+extern crate lua;
+
+#[cfg(test)]
+mod test {
+    use lua::Lua;
+    use lua::status::LuaOk;
+    use lua::traits::{ToLua, FromLua};
+
+    fn answer(lua: &Lua) -> int {
+        lua.push(42);
+        1
+    }
+
+    fn open(lua: &Lua) -> int {
+        lua.new_lib([("answer", answer)]);
+        1
+    }
+
+    lua_module!(luaopen_test, open)
+
+    #[deriving(Eq, Show)]
+    struct Config {
+        name: String,
+        size: uint,
+        comment: Option<String>,
+    }
+
+    lua_struct!(Config { name as "title", size = 10, comment })
+
+    #[deriving(Eq, Show)]
+    enum Shape {
+        Point,
+        Circle(f64),
+        Rect(f64, f64),
+    }
+
+    lua_enum!(Shape { Point as "point", Circle(radius), Rect(width, height) })
+
+    /// Evaluate a Lua expression and convert its value.
+    fn eval<T: FromLua>(lua: &Lua, expr: &str) -> Option<T> {
+        assert_eq!(lua.exec_str(format!("value = {}", expr).as_slice()), LuaOk);
+        let value = lua.get_global("value");
+        lua.state.pop(1);
+        value
+    }
+
+    /// Convert a value to Lua and back, checking its Lua value with `check`.
+    fn round_trip<T: ToLua + FromLua>(lua: &Lua, val: T, check: &str) -> Option<T> {
+        lua.set_global("value", val);
+        assert_eq!(lua.exec_str(format!("assert({})", check).as_slice()), LuaOk);
+        let value = lua.get_global("value");
+        lua.state.pop(1);
+        value
+    }
+
+    #[test]
+    fn test_lua_module()
+    {
+        let lua = Lua::new();
+        lua.state.load_stdlibs();
+
+        // Loaded like a native module by `require`.
+        lua.state.get_global("package");
+        lua.state.get_field(-1, "preload");
+        lua.state.push_cfunction(luaopen_test);
+        lua.state.set_field(-2, "test");
+        lua.state.pop(2);
+
+        assert_eq!(lua.exec_str("answer = require('test').answer()"), LuaOk);
+        assert_eq!(lua.get_global("answer"), Some(42));
+    }
+
+    #[test]
+    fn test_lua_struct()
+    {
+        let lua = Lua::new();
+        lua.state.load_stdlibs();
+
+        let config = Config { name: "a".to_string(), size: 3, comment: Some("b".to_string()) };
+        let check = "value.title == 'a' and value.name == nil and value.size == 3 and value.comment == 'b'";
+        assert_eq!(round_trip(&lua, config, check),
+                   Some(Config { name: "a".to_string(), size: 3, comment: Some("b".to_string()) }));
+
+        let config = Config { name: "a".to_string(), size: 3, comment: None };
+        let check = "value.comment == nil";
+        assert_eq!(round_trip(&lua, config, check),
+                   Some(Config { name: "a".to_string(), size: 3, comment: None }));
+
+        // Missing fields with a default, or Option fields.
+        assert_eq!(eval(&lua, "{title = 'a'}"), Some(Config { name: "a".to_string(), size: 10, comment: None }));
+
+        // Missing fields without default, or fields of the wrong type.
+        let config: Option<Config> = eval(&lua, "{name = 'a'}");
+        assert_eq!(config, None);
+        let config: Option<Config> = eval(&lua, "{title = 'a', size = 'big'}");
+        assert_eq!(config, None);
+        let config: Option<Config> = eval(&lua, "'a'");
+        assert_eq!(config, None);
+    }
+
+    #[test]
+    fn test_lua_enum()
+    {
+        let lua = Lua::new();
+        lua.state.load_stdlibs();
+
+        assert_eq!(round_trip(&lua, Point, "value == 'point'"), Some(Point));
+        assert_eq!(round_trip(&lua, Circle(2.0), "value.tag == 'Circle' and value.radius == 2"), Some(Circle(2.0)));
+        assert_eq!(round_trip(&lua, Rect(1.0, 2.0), "value.tag == 'Rect' and value.width == 1 and value.height == 2"),
+                   Some(Rect(1.0, 2.0)));
+
+        // Unknown or renamed variants, variants with arguments as strings and the other way around.
+        for expr in ["'Point'", "'square'", "{tag = 'point'}", "'Circle'", "{tag = 'Rect', width = 1}", "{radius = 1}"].iter() {
+            let shape: Option<Shape> = eval(&lua, *expr);
+            assert_eq!(shape, None);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::io;
+use std::str;
+
+use lua::Lua;
+use lua::bytecode::{Chunk, Prototype, ConstString};
+use lua::opcodes::{Instruction, OpSetTabUp, is_constant, constant_index};
+use lua::status::LuaOk;
+
+static USAGE: &'static str = "usage: rlua check [-g] file.lua...";
+
+/**
+ *  Check scripts syntax without running them, like `luac -p`.
+ *
+ *  Errors are printed as `file:line: message`. With `-g`, assignments to global variables
+ *  are reported too. Return false if any script has an error.
+ */
+pub fn run(args: &[String]) -> bool {
+    let mut globals = false;
+    let mut files = Vec::new();
+    for arg in args.iter() {
+        match arg.as_slice() {
+            "-g" => globals = true,
+            _ => files.push(arg.as_slice()),
+        }
+    }
+
+    if files.is_empty() {
+        let _ = writeln!(io::stderr(), "{}", USAGE);
+        return false;
+    }
+
+    let lua = Lua::new();
+    let mut ok = true;
+    for filename in files.iter() {
+        match lua.state.load_file(*filename) {
+            LuaOk => {}
+            _ => {
+                let msg = lua.state.to_display_str(-1);
+                let _ = writeln!(io::stderr(), "{}", msg);
+                lua.state.pop(1);
+                ok = false;
+                continue;
+            }
+        }
+
+        if globals {
+            let chunk = lua.dump(false).ok().and_then(|bytes| Chunk::parse(bytes.as_slice()).ok());
+            match chunk {
+                Some(chunk) => {
+                    let mut found = global_assignments(&chunk.main);
+                    found.sort();
+                    for &(line, ref name) in found.iter() {
+                        let _ = writeln!(io::stderr(), "{}:{}: assignment to global '{}'", filename, line, name);
+                        ok = false;
+                    }
+                }
+                None => {
+                    let _ = writeln!(io::stderr(), "{}: unable to inspect the compiled chunk", filename);
+                    ok = false;
+                }
+            }
+        }
+        lua.state.pop(1);
+    }
+    ok
+}
+
+/**
+ *  Find the assignments to global variables in a function and its nested functions,
+ *  returning their line and variable name.
+ *
+ *  Global assignments are compiled to `SETTABUP _ENV "name" value`.
+ */
+fn global_assignments(p: &Prototype) -> Vec<(int, String)> {
+    let mut found = Vec::new();
+    for (pc, &code) in p.code.iter().enumerate() {
+        let i = Instruction(code);
+        if i.opcode() != Some(OpSetTabUp) || !is_constant(i.b()) {
+            continue;
+        }
+
+        match p.upvalue_names.as_slice().get(i.a()) {
+            Some(upvalue) if upvalue.as_slice() == "_ENV" => {}
+            _ => continue,
+        }
+
+        match p.constants.as_slice().get(constant_index(i.b())) {
+            Some(&ConstString(ref name)) => {
+                let line = p.line_info.as_slice().get(pc).map(|line| *line).unwrap_or(0);
+                let name = str::from_utf8_lossy(name.as_slice()).into_string();
+                found.push((line, name));
+            }
+            _ => {}
+        }
+    }
+
+    for nested in p.prototypes.iter() {
+        found.push_all_move(global_assignments(nested));
+    }
+    found
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{File, TempDir};
+
+    use lua::Lua;
+    use lua::bytecode::Chunk;
+    use lua::status::LuaOk;
+
+    use super::{run, global_assignments};
+
+    /// Run the check on a script, with the given options.
+    fn check(source: &str, options: &[&str]) -> bool {
+        let dir = TempDir::new("rlua-check").unwrap();
+        let script = dir.path().join("script.lua");
+        File::create(&script).write_str(source).unwrap();
+
+        let mut args: Vec<String> = options.iter().map(|option| option.to_string()).collect();
+        args.push(script.as_str().unwrap().to_string());
+        run(args.as_slice())
+    }
+
+    fn globals(source: &str) -> Vec<(int, String)> {
+        let lua = Lua::new();
+        assert_eq!(lua.state.load_str(source), LuaOk);
+        let chunk = Chunk::parse(lua.dump(false).unwrap().as_slice()).unwrap();
+        global_assignments(&chunk.main)
+    }
+
+    #[test]
+    fn test_check()
+    {
+        assert!(check("local x = 1", []));
+        assert!(!check("local x = ", []));
+        assert!(!check("x = 1", ["-g"]));
+        assert!(check("local x = 1", ["-g"]));
+        assert!(check("t.x = 1", ["-g"]));
+        assert!(!run([]));
+    }
+
+    #[test]
+    fn test_global_assignments()
+    {
+        assert_eq!(globals("x = 1"), vec![(1, "x".to_string())]);
+        assert_eq!(globals("local x = 1"), vec![]);
+        assert_eq!(globals("local t = {}\nt.x = 1"), vec![]);
+        assert_eq!(globals("t.x = 1"), vec![]);
+        assert_eq!(globals("local function f()\n  y = 2\nend"), vec![(2, "y".to_string())]);
+    }
+}
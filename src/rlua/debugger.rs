@@ -0,0 +1,377 @@
+use std::io;
+
+use lua::Lua;
+use lua::ffi;
+use lua::load::Text;
+use lua::status::LuaOk;
+use lua::debug::{
+    ActivationRecord,
+    DebugEvent,
+    HookCall,
+    HookReturn,
+    HookLine,
+    MASK_CALL,
+    MASK_RET,
+    MASK_LINE,
+};
+
+static HELP: &'static str = "\
+Commands:
+  b, break FILE:LINE    set a breakpoint
+  d, delete [N]         delete breakpoint N, or all breakpoints
+  i, info               list breakpoints
+  c, continue           continue until next breakpoint
+  s, step               step into next line
+  n, next               step over next line
+  f, finish             continue until current function returns
+  bt, backtrace         print the call stack
+  l, locals             print local variables
+  p, print EXPR         evaluate an expression in the current frame
+  q, quit               abort the script
+  h, help               print this message";
+
+enum StepMode {
+    Continue,
+    Step,
+    Next(int),
+    Finish(int),
+}
+
+struct Breakpoint {
+    file: String,
+    line: int,
+}
+
+impl Breakpoint {
+    fn matches(&self, source: &str, line: int) -> bool {
+        if self.line != line || !source.starts_with("@") {
+            return false;
+        }
+
+        let filename = source.slice_from(1);
+        filename == self.file.as_slice() || filename.ends_with(format!("/{}", self.file).as_slice())
+    }
+}
+
+/**
+ *  Line-based interactive debugger.
+ *
+ *  Start paused on the first executed line, then read commands from stdin.
+ */
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    mode: StepMode,
+    depth: int,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: Vec::new(),
+            mode: Step,
+            depth: 0,
+        }
+    }
+
+    /// Install the debugger hook on a Lua state.
+    pub fn attach<'a>(&'a mut self, lua: &Lua<'a>) {
+        lua.set_hook(MASK_CALL | MASK_RET | MASK_LINE, 0, |lua, event, ar| {
+            self.on_event(lua, event, ar)
+        });
+    }
+
+    fn on_event(&mut self, lua: &Lua, event: DebugEvent, ar: &mut ActivationRecord) -> Result<(), String> {
+        match event {
+            HookCall => self.depth += 1,
+            HookReturn => self.depth -= 1,
+            HookLine(line) => {
+                if self.should_pause(lua, ar, line) {
+                    return self.prompt(lua, ar);
+                }
+            }
+            // A tail call replaces the current frame, depth doesn't change.
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn should_pause(&self, lua: &Lua, ar: &mut ActivationRecord, line: int) -> bool {
+        let stop = match self.mode {
+            Continue => false,
+            Step => true,
+            Next(depth) => self.depth <= depth,
+            Finish(depth) => self.depth < depth,
+        };
+
+        if stop || self.breakpoints.is_empty() {
+            return stop;
+        }
+
+        let info = ar.info(&lua.state);
+        self.breakpoints.iter().any(|bp| bp.matches(info.source.as_slice(), line))
+    }
+
+    fn prompt(&mut self, lua: &Lua, ar: &mut ActivationRecord) -> Result<(), String> {
+        let info = ar.info(&lua.state);
+        println!("{}:{}: {}", info.short_src, info.current_line, info.name.unwrap_or("?".to_string()));
+
+        let mut stdin = io::stdin();
+        loop {
+            print!("(rlua-dbg) ");
+            io::stdio::flush();
+
+            let line = match stdin.read_line() {
+                Ok(line) => line,
+                Err(_) => return Err("debugger: end of input".to_string()),
+            };
+            let line = line.as_slice().trim();
+            let (cmd, arg) = match line.find(' ') {
+                Some(i) => (line.slice_to(i), line.slice_from(i + 1).trim()),
+                None => (line, ""),
+            };
+
+            match cmd {
+                "" => {}
+                "b" | "break" => self.add_breakpoint(arg),
+                "d" | "delete" => self.delete_breakpoint(arg),
+                "i" | "info" => {
+                    for (i, bp) in self.breakpoints.iter().enumerate() {
+                        println!("{}: {}:{}", i + 1, bp.file, bp.line);
+                    }
+                }
+                "c" | "continue" => {
+                    self.mode = Continue;
+                    return Ok(());
+                }
+                "s" | "step" => {
+                    self.mode = Step;
+                    return Ok(());
+                }
+                "n" | "next" => {
+                    self.mode = Next(self.depth);
+                    return Ok(());
+                }
+                "f" | "finish" => {
+                    self.mode = Finish(self.depth);
+                    return Ok(());
+                }
+                "bt" | "backtrace" => print_backtrace(lua),
+                "l" | "locals" => print_locals(lua, ar),
+                "p" | "print" => match eval(lua, ar, arg) {
+                    Ok(values) => println!("{}", values.connect("\t")),
+                    Err(msg) => println!("error: {}", msg),
+                },
+                "q" | "quit" => return Err("debugger: quit".to_string()),
+                "h" | "help" => println!("{}", HELP),
+                _ => println!("Unknown command `{}`, try `help`.", cmd),
+            }
+        }
+    }
+
+    fn add_breakpoint(&mut self, arg: &str) {
+        let bp = arg.rfind(':').and_then(|i| {
+            from_str::<int>(arg.slice_from(i + 1)).map(|line| Breakpoint {
+                file: arg.slice_to(i).to_string(),
+                line: line,
+            })
+        });
+
+        match bp {
+            Some(bp) => {
+                println!("Breakpoint {} at {}:{}", self.breakpoints.len() + 1, bp.file, bp.line);
+                self.breakpoints.push(bp);
+            }
+            None => println!("Usage: break FILE:LINE"),
+        }
+    }
+
+    fn delete_breakpoint(&mut self, arg: &str) {
+        if arg.is_empty() {
+            self.breakpoints.clear();
+            return;
+        }
+
+        match from_str::<uint>(arg) {
+            Some(n) if n >= 1 && n <= self.breakpoints.len() => {
+                self.breakpoints.remove(n - 1);
+            }
+            _ => println!("No breakpoint `{}`.", arg),
+        }
+    }
+}
+
+fn print_backtrace(lua: &Lua) {
+    for (level, info) in lua.backtrace().iter().enumerate() {
+        let name = match info.name {
+            Some(ref name) => format!("{} '{}'", info.namewhat, name),
+            None if info.what.as_slice() == "main" => "main chunk".to_string(),
+            None => "?".to_string(),
+        };
+
+        match info.current_line {
+            -1 => println!("#{} {}: in {}", level, info.short_src, name),
+            line => println!("#{} {}:{}: in {}", level, info.short_src, line, name),
+        }
+    }
+}
+
+fn print_locals(lua: &Lua, ar: &ActivationRecord) {
+    let mut n = 1;
+    loop {
+        let name = match lua.state.get_local(ar, n) {
+            Some(name) => name,
+            None => break,
+        };
+        let value = lua.state.to_display_str(-1);
+        lua.state.pop(1);
+
+        // Skip internal variables, like "(for index)".
+        if !name.as_slice().starts_with("(") {
+            println!("{} = {}", name, value);
+        }
+        n += 1;
+    }
+}
+
+/**
+ *  Evaluate an expression in the frame of an activation record.
+ *
+ *  Locals and upvalues are visible through the chunk environment, which falls back to globals.
+ */
+fn eval(lua: &Lua, ar: &mut ActivationRecord, expr: &str) -> Result<Vec<String>, String> {
+    let state = &lua.state;
+    let top = state.get_top();
+
+    state.new_table();
+    let env = state.get_top();
+
+    // Upvalues first, so locals shadow them.
+    ar.get_info(state, "f");
+    let mut n = 1;
+    loop {
+        match state.get_upvalue(-1, n) {
+            Some(name) => state.set_field(env, name.as_slice()),
+            None => break,
+        }
+        n += 1;
+    }
+    state.pop(1);
+
+    let mut n = 1;
+    loop {
+        match state.get_local(ar, n) {
+            Some(name) => {
+                if name.as_slice().starts_with("(") {
+                    state.pop(1);
+                } else {
+                    state.set_field(env, name.as_slice());
+                }
+            }
+            None => break,
+        }
+        n += 1;
+    }
+
+    state.new_table();
+    state.push_globals();
+    state.set_field(-2, "__index");
+    state.set_metatable(env);
+
+    let status = match state.load_strx(format!("return {}", expr).as_slice(), Text) {
+        LuaOk => LuaOk,
+        _ => {
+            state.pop(1);
+            state.load_strx(expr, Text)
+        }
+    };
+    if status == LuaOk {
+        state.push_value(env);
+        state.set_upvalue(-2, 1);
+    }
+
+    let result = match status {
+        LuaOk => match state.pcall(0, ffi::LUA_MULTRET as int, 0) {
+            LuaOk => {
+                let mut values = Vec::new();
+                let mut idx = env + 1;
+                while idx <= state.get_top() {
+                    values.push(state.to_display_str(idx));
+                    idx += 1;
+                }
+                Ok(values)
+            }
+            _ => Err(state.to_display_str(-1)),
+        },
+        _ => Err(state.to_display_str(-1)),
+    };
+
+    state.set_top(top);
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use lua::Lua;
+    use lua::status::LuaOk;
+    use lua::debug::{HookLine, MASK_LINE};
+
+    use super::{Breakpoint, Debugger, eval};
+
+    #[test]
+    fn test_breakpoint_matches()
+    {
+        let bp = Breakpoint { file: "foo.lua".to_string(), line: 3 };
+        assert!(bp.matches("@foo.lua", 3));
+        assert!(bp.matches("@src/foo.lua", 3));
+        assert!(!bp.matches("@src/foo.lua", 4));
+        assert!(!bp.matches("@barfoo.lua", 3));
+        assert!(!bp.matches("=foo.lua", 3));
+    }
+
+    #[test]
+    fn test_breakpoints()
+    {
+        let mut debugger = Debugger::new();
+        debugger.add_breakpoint("a.lua:1");
+        debugger.add_breakpoint("b.lua:2");
+        debugger.add_breakpoint("invalid");
+        assert_eq!(debugger.breakpoints.len(), 2);
+
+        debugger.delete_breakpoint("1");
+        assert_eq!(debugger.breakpoints.len(), 1);
+        assert_eq!(debugger.breakpoints.as_slice()[0].file.as_slice(), "b.lua");
+        debugger.delete_breakpoint("3");
+        assert_eq!(debugger.breakpoints.len(), 1);
+        debugger.delete_breakpoint("");
+        assert!(debugger.breakpoints.is_empty());
+    }
+
+    #[test]
+    fn test_eval()
+    {
+        let mut results = Vec::new();
+        {
+            let lua = Lua::new();
+            lua.set_hook(MASK_LINE, 0, |lua, event, ar| {
+                match event {
+                    HookLine(3) => {
+                        // `a` is an upvalue, `x` a local.
+                        results.push(eval(lua, ar, "a * x"));
+                        results.push(eval(lua, ar, "x + nil"));
+                    }
+                    _ => {}
+                }
+                Ok(())
+            });
+            assert_eq!(lua.exec_str("local a = 10\n\
+                                     local function f(x)\n\
+                                         return a * x\n\
+                                     end\n\
+                                     f(5)"), LuaOk);
+        }
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(results.as_slice()[0], Ok(vec!["50".to_string()]));
+        assert!(results.as_slice()[1].is_err());
+    }
+}
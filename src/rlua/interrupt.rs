@@ -0,0 +1,45 @@
+use std::mem::transmute;
+
+use libc;
+use libc::{c_int, size_t};
+
+use lua::Lua;
+use lua::interrupt::InterruptHandle;
+
+type SigHandler = size_t;
+
+static SIG_DFL: SigHandler = 0;
+
+extern {
+    fn signal(signum: c_int, handler: SigHandler) -> SigHandler;
+}
+
+/// Handle of the state running the current script, for `laction`.
+static mut HANDLE: Option<InterruptHandle> = None;
+
+extern "C" fn laction(i: c_int) {
+    unsafe {
+        // If another SIGINT happens before the script stops, terminate the process.
+        signal(i, SIG_DFL);
+        match HANDLE {
+            Some(ref handle) => handle.interrupt(),
+            None => {}
+        }
+    }
+}
+
+/// Interrupt the scripts run by `lua` on SIGINT, until `reset` is called, which must happen before `lua` is closed.
+pub fn catch(lua: &Lua) {
+    unsafe {
+        HANDLE = Some(lua.interrupt_handle());
+        signal(libc::SIGINT, transmute(laction));
+    }
+}
+
+/// Restore the default SIGINT behaviour.
+pub fn reset() {
+    unsafe {
+        signal(libc::SIGINT, SIG_DFL);
+        HANDLE = None;
+    }
+}
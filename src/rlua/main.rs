@@ -5,39 +5,696 @@
 #![feature(globs)]
 
 extern crate lua;
+extern crate libc;
 
 use std::os;
 use std::io;
-use std::str;
+use std::io::File;
+use libc::c_int;
 
 use lua::Lua;
+use lua::ffi;
+use lua::json;
+use lua::disasm::write_listing;
+use lua::bytecode::Chunk;
+use lua::state::State;
+use lua::types::{LuaNone, LuaNil, LuaString, LuaNumber};
+use lua::profiler::{Profiler, Tracing};
+use lua::coverage::Coverage;
 use lua::status::{
+    LuaStatus,
     LuaOk,
     LuaErr,
 };
 
+use debugger::Debugger;
+
+mod check;
+mod debugger;
+mod interrupt;
+mod readline;
+mod repl;
+
+static PROGNAME: &'static str = "rlua";
+
+/// Environment variables holding code to run at startup, by priority.
+static INIT_VARS: [&'static str, ..2] = ["LUA_INIT_5_2", "LUA_INIT"];
+
+/// Command line actions, run in order before the script.
+enum Action {
+    /// `-e stat`
+    ExecString(String),
+    /// `-l name`
+    Require(String),
+}
+
+struct Options {
+    /// Index of the script in the arguments.
+    script: Option<uint>,
+    interactive: bool,
+    version: bool,
+    no_env: bool,
+    actions: Vec<Action>,
+    debug: bool,
+    profile: Option<String>,
+    coverage: Option<String>,
+}
+
+impl Options {
+    fn new() -> Options {
+        Options {
+            script: None,
+            interactive: false,
+            version: false,
+            no_env: false,
+            actions: Vec::new(),
+            debug: false,
+            profile: None,
+            coverage: None,
+        }
+    }
+}
+
+/// Precompile a script, like `luac`.
+fn compile(args: &[String])
+{
+    let mut input = None;
+    let mut output = "luac.out".to_string();
+    let mut strip = false;
+    let mut iter = args.iter();
+    loop {
+        let arg = match iter.next() {
+            Some(arg) => arg,
+            None => break,
+        };
+
+        match arg.as_slice() {
+            "-o" => match iter.next() {
+                Some(filename) => output = filename.clone(),
+                None => {
+                    l_message("'-o' needs argument");
+                    os::set_exit_status(1);
+                    return;
+                }
+            },
+            "-s" => strip = true,
+            _ if input.is_none() => input = Some(arg.clone()),
+            _ => {}
+        }
+    }
+
+    let input = match input {
+        Some(input) => input,
+        None => {
+            l_message("usage: rlua compile in.lua [-o out.luac] [-s]");
+            os::set_exit_status(1);
+            return;
+        }
+    };
+
+    let lua = Lua::new();
+    match lua.state.load_file(input.as_slice()) {
+        LuaOk => {}
+        status => {
+            report(&lua, status);
+            os::set_exit_status(1);
+            return;
+        }
+    }
+
+    let mut file = File::create(&Path::new(output.as_slice()));
+    match lua.dump_to(&mut file, strip) {
+        Ok(()) => {}
+        Err(err) => {
+            l_message(format!("cannot write {}: {}", output, err).as_slice());
+            os::set_exit_status(1);
+        }
+    }
+}
+
+/// List the bytecode of scripts or precompiled chunks, like `luac -l -l`.
+fn disasm(args: &[String])
+{
+    let mut full = true;
+    let mut files = Vec::new();
+    for arg in args.iter() {
+        match arg.as_slice() {
+            "-c" => full = false,
+            _ => files.push(arg.as_slice()),
+        }
+    }
+
+    if files.is_empty() {
+        l_message("usage: rlua disasm [-c] file...");
+        os::set_exit_status(1);
+        return;
+    }
+
+    let lua = Lua::new();
+    let mut stdout = io::stdout();
+    for filename in files.iter() {
+        match lua.state.load_file(*filename) {
+            LuaOk => {}
+            status => {
+                report(&lua, status);
+                os::set_exit_status(1);
+                return;
+            }
+        }
+
+        let chunk = lua.dump(false).ok().and_then(|bytes| Chunk::parse(bytes.as_slice()).ok());
+        lua.state.pop(1);
+        match chunk {
+            Some(chunk) => write_listing(&mut stdout, &chunk, full).unwrap(),
+            None => {
+                l_message(format!("{}: unable to parse the compiled chunk", filename).as_slice());
+                os::set_exit_status(1);
+                return;
+            }
+        }
+    }
+}
+
+fn print_usage(badoption: &str)
+{
+    let mut stderr = io::stderr();
+    let _ = write!(stderr, "{}: ", PROGNAME);
+    if badoption.starts_with("-e") || badoption.starts_with("-l") {
+        let _ = writeln!(stderr, "'{}' needs argument", badoption);
+    } else {
+        let _ = writeln!(stderr, "unrecognized option '{}'", badoption);
+    }
+    let _ = write!(stderr, "usage: {} [options] [script [args]]
+Available options are:
+  -e stat  execute string 'stat'
+  -i       enter interactive mode after executing 'script'
+  -l name  require library 'name'
+  -v       show version information
+  -E       ignore environment variables
+  --       stop handling options
+  -        stop handling options and execute stdin
+  --debug          run 'script' under the debugger
+  --profile FILE   write a profile of the run into FILE
+  --coverage FILE  write the line coverage of the run into FILE
+", PROGNAME);
+}
+
+/**
+ *  Parse the options preceding the script, like `lua.c`.
+ *
+ *  Return the faulty option on error.
+ */
+fn collect_args(args: &[String]) -> Result<Options, String>
+{
+    let mut opts = Options::new();
+    let mut i = 1;
+    while i < args.len() {
+        let arg = args[i].as_slice();
+        if !arg.starts_with("-") {
+            opts.script = Some(i);
+            break;
+        }
+
+        match arg {
+            "--" => {
+                if i + 1 < args.len() {
+                    opts.script = Some(i + 1);
+                }
+                break;
+            }
+            "-" => {
+                opts.script = Some(i);
+                break;
+            }
+            "-i" => {
+                opts.interactive = true;
+                opts.version = true;
+            }
+            "-v" => opts.version = true,
+            "-E" => opts.no_env = true,
+            "--debug" => opts.debug = true,
+            "--profile" | "--coverage" => {
+                i += 1;
+                let filename = match args.get(i) {
+                    Some(filename) => Some(filename.clone()),
+                    None => return Err(arg.to_string()),
+                };
+                match arg {
+                    "--profile" => opts.profile = filename,
+                    _ => opts.coverage = filename,
+                }
+            }
+            _ if arg.starts_with("-e") || arg.starts_with("-l") => {
+                // The argument is either attached to the option or the next one.
+                let value = if arg.len() > 2 {
+                    arg.slice_from(2).to_string()
+                } else {
+                    i += 1;
+                    match args.get(i) {
+                        Some(value) if !value.as_slice().starts_with("-") => value.clone(),
+                        _ => return Err(arg.to_string()),
+                    }
+                };
+                opts.actions.push(match arg.char_at(1) {
+                    'e' => ExecString(value),
+                    _ => Require(value),
+                });
+            }
+            _ => return Err(arg.to_string()),
+        }
+        i += 1;
+    }
+    Ok(opts)
+}
+
+/**
+ *  Check the options installing a debug hook, as a state has a single hook.
+ *
+ *  Return the error message on incompatible options.
+ */
+fn check_hooks(opts: &Options) -> Option<&'static str>
+{
+    let hooks = [opts.debug, opts.profile.is_some(), opts.coverage.is_some()];
+    if hooks.iter().filter(|&&hook| hook).count() > 1 {
+        return Some("'--debug', '--profile' and '--coverage' can't be combined");
+    }
+    if opts.debug && opts.script.is_none() {
+        return Some("'--debug' needs a script file, as commands are read from stdin");
+    }
+    None
+}
+
+/// Print a message to stderr, prefixed by the program name.
+fn l_message(msg: &str)
+{
+    let _ = writeln!(io::stderr(), "{}: {}", PROGNAME, msg);
+}
+
+/**
+ *  Message handler adding a traceback to error messages.
+ *
+ *  Non-string error objects are converted with their `__tostring` metamethod, if any.
+ */
+extern "C" fn _lua_traceback(raw: *ffi::lua_State) -> c_int {
+    let lua = Lua::from_state(unsafe {
+        State::from_ffi(raw)
+    });
+
+    match lua.state.get_type(1) {
+        LuaString | LuaNumber => {
+            let msg = lua.state.get_str(1);
+            lua.state.traceback(Some(msg.as_slice()), 1);
+        }
+        LuaNone | LuaNil => {}
+        _ => {
+            if !lua.state.call_meta(1, "__tostring") {
+                lua.state.push_str("(no error message)");
+            }
+        }
+    }
+    1
+}
+
+/**
+ *  Call the function below its `nargs` arguments, with a traceback on errors and interrupted on SIGINT.
+ *
+ *  The error message is left on the stack, to be printed by `report`.
+ */
+pub fn docall(lua: &Lua, nargs: int, nresults: int) -> LuaStatus
+{
+    let base = lua.state.get_top() - nargs;
+    lua.state.push_cfunction(_lua_traceback);
+    lua.state.insert(base);
+    interrupt::catch(lua);
+    let status = lua.state.pcall(nargs, nresults, base);
+    interrupt::reset();
+    lua.state.remove(base);
+    status
+}
+
+/// Print and pop the error message at the top of the stack, if `status` is an error.
+pub fn report(lua: &Lua, status: LuaStatus) -> LuaStatus
+{
+    match status {
+        LuaOk => return status,
+        LuaErr(_) => {}
+    }
+
+    match lua.state.get_type(-1) {
+        LuaNil => return status,
+        LuaString | LuaNumber => l_message(lua.state.get_str(-1).as_slice()),
+        _ => l_message("(error object is not a string)"),
+    }
+    lua.state.pop(1);
+    // Force a complete garbage collection in case of errors.
+    lua.state.gc(ffi::LUA_GCCOLLECT as int, 0);
+    status
+}
+
+/// Call the chunk loaded with `status`, discarding its results.
+fn dochunk(lua: &Lua, status: LuaStatus) -> LuaStatus
+{
+    match status {
+        LuaOk => docall(lua, 0, 0),
+        status => status,
+    }
+}
+
+/// Run `require(name)`, storing the module in the global `name`.
+fn dolibrary(lua: &Lua, name: &str) -> LuaStatus
+{
+    lua.state.get_global("require");
+    lua.state.push_str(name);
+    match docall(lua, 1, 1) {
+        LuaOk => {
+            lua.state.set_global(name);
+            LuaOk
+        }
+        status => status,
+    }
+}
+
+/// Run the content of `LUA_INIT_5_2` or `LUA_INIT`, either code or `@filename`.
+fn handle_luainit(lua: &Lua) -> LuaStatus
+{
+    for name in INIT_VARS.iter() {
+        match os::getenv(*name) {
+            Some(init) => {
+                let status = if init.as_slice().starts_with("@") {
+                    lua.load_file(init.as_slice().slice_from(1))
+                } else {
+                    let chunkname = format!("={}", name);
+                    lua.state.load_buffer(init.as_bytes(), chunkname.as_slice(), lua.load_mode())
+                };
+                return dochunk(lua, status);
+            }
+            None => {}
+        }
+    }
+    LuaOk
+}
+
+/**
+ *  Run the script at `args[script]` with the following arguments as varargs.
+ *
+ *  Every argument is also stored in the global `arg` table, the script being at index 0,
+ *  the interpreter and options at negative indices.
+ */
+fn handle_script(lua: &Lua, args: &[String], script: uint) -> LuaStatus
+{
+    lua.state.create_table((args.len() - script - 1) as int, (script + 1) as int);
+    for (i, arg) in args.iter().enumerate() {
+        lua.state.push_str(arg.as_slice());
+        lua.state.raw_set_index(-2, i as int - script as int);
+    }
+    lua.state.set_global("arg");
+
+    let fname = args[script].as_slice();
+    let status = if fname == "-" && args[script - 1].as_slice() != "--" {
+        lua.state.load_reader(&mut io::stdin(), "=stdin", lua.load_mode())
+    } else {
+        lua.load_file(fname)
+    };
+    match status {
+        LuaOk => {}
+        status => return status,
+    }
+
+    let script_args = args.slice_from(script + 1);
+    for arg in script_args.iter() {
+        lua.state.push_str(arg.as_slice());
+    }
+    docall(lua, script_args.len() as int, 0)
+}
+
+/// Run everything asked on the command line, stopping at the first error.
+fn run(lua: &Lua, opts: &Options, args: &[String]) -> LuaStatus
+{
+    if !opts.no_env {
+        match handle_luainit(lua) {
+            LuaOk => {}
+            status => return status,
+        }
+    }
+
+    for action in opts.actions.iter() {
+        let status = match *action {
+            ExecString(ref stat) => {
+                let status = lua.state.load_buffer(stat.as_bytes(), "=(command line)", lua.load_mode());
+                dochunk(lua, status)
+            }
+            Require(ref name) => dolibrary(lua, name.as_slice()),
+        };
+        match status {
+            LuaOk => {}
+            status => return status,
+        }
+    }
+
+    match opts.script {
+        Some(script) => match handle_script(lua, args, script) {
+            LuaOk => {}
+            status => return status,
+        },
+        None => {}
+    }
+
+    if opts.interactive {
+        repl::run(lua);
+    } else if opts.script.is_none() && opts.actions.is_empty() && !opts.version {
+        if unsafe { libc::isatty(libc::STDIN_FILENO) } != 0 {
+            println!("{}", ffi::LUA_COPYRIGHT);
+            repl::run(lua);
+        } else {
+            let status = lua.state.load_reader(&mut io::stdin(), "=stdin", lua.load_mode());
+            return dochunk(lua, status);
+        }
+    }
+    LuaOk
+}
+
 fn main()
 {
     let args = os::args();
 
+    if args.len() > 1 && args.get(1).as_slice() == "compile" {
+        compile(args.slice_from(2));
+        return;
+    }
+
+    if args.len() > 1 && args.get(1).as_slice() == "disasm" {
+        disasm(args.slice_from(2));
+        return;
+    }
+
+    if args.len() > 1 && args.get(1).as_slice() == "check" {
+        if !check::run(args.slice_from(2)) {
+            os::set_exit_status(1);
+        }
+        return;
+    }
+
+    let opts = match collect_args(args.as_slice()) {
+        Ok(opts) => opts,
+        Err(badoption) => {
+            print_usage(badoption.as_slice());
+            os::set_exit_status(1);
+            return;
+        }
+    };
+
+    match check_hooks(&opts) {
+        Some(msg) => {
+            l_message(msg);
+            os::set_exit_status(1);
+            return;
+        }
+        None => {}
+    }
+
+    if opts.version {
+        println!("{}", ffi::LUA_COPYRIGHT);
+    }
+
+    let mut debugger = Debugger::new();
+    let profiler = Profiler::new(Tracing);
+    let coverage = Coverage::new();
+
     let lua = Lua::new();
+    if opts.no_env {
+        // Tell the package library to ignore LUA_PATH and LUA_CPATH.
+        lua.push(true);
+        lua.state.set_field(ffi::LUA_REGISTRYINDEX as int, "LUA_NOENV");
+    }
     lua.state.load_stdlibs();
+    // Preloaded, so scripts only get it through `require`, before any `json.lua` of the path.
+    lua.register_module("json", json::open);
 
-    let status = if args.len() == 1 {
-        let program = io::stdin().read_to_end().unwrap();
-        let program = str::from_utf8(program.as_slice()).unwrap();
+    if opts.debug {
+        debugger.attach(&lua);
+    } else if opts.profile.is_some() {
+        lua.set_profiler(&profiler);
+    } else if opts.coverage.is_some() {
+        lua.set_coverage(&coverage);
+    }
 
-        lua.exec_str(program)
-    } else {
-        lua.exec_file(args.get(1).as_slice())
-    };
+    let status = run(&lua, &opts, args.as_slice());
 
-    match status {
+    lua.remove_hook();
+
+    match (&opts.profile, &opts.coverage) {
+        _ if opts.debug => {}
+        (&Some(ref filename), _) => {
+            let mut file = File::create(&Path::new(filename.as_slice()));
+            match profiler.write_folded(&mut file) {
+                Ok(()) => {}
+                Err(err) => {
+                    l_message(format!("cannot write {}: {}", filename, err).as_slice());
+                    os::set_exit_status(1);
+                }
+            }
+            let _ = profiler.write_summary(&mut io::stderr());
+        }
+        (&None, &Some(ref filename)) => {
+            let mut file = File::create(&Path::new(filename.as_slice()));
+            // Cobertura for XML output, lcov otherwise.
+            let result = match filename.as_slice().ends_with(".xml") {
+                true => coverage.write_cobertura(&mut file),
+                false => coverage.write_lcov(&mut file),
+            };
+            match result {
+                Ok(()) => {}
+                Err(err) => {
+                    l_message(format!("cannot write {}: {}", filename, err).as_slice());
+                    os::set_exit_status(1);
+                }
+            }
+        }
+        (&None, &None) => {}
+    }
+
+    match report(&lua, status) {
         LuaOk => {}
-        LuaErr(e) => {
-            let msg: String = lua.get(-1).unwrap();
+        LuaErr(_) => os::set_exit_status(1),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{File, TempDir};
+
+    use lua::Lua;
+    use lua::status::{LuaOk, LuaErr, RuntimeError};
 
-            fail!("Lua {}: {}", e, msg);
+    use super::{collect_args, check_hooks, handle_script, docall, report, ExecString, Require};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_collect_args()
+    {
+        let opts = collect_args(args(["rlua", "-e", "x = 1", "-lfoo", "-i", "-E", "script.lua", "-v"]).as_slice()).unwrap();
+        assert_eq!(opts.script, Some(6));
+        assert!(opts.interactive && opts.version && opts.no_env);
+        assert_eq!(opts.actions.len(), 2);
+        match opts.actions.as_slice()[0] {
+            ExecString(ref stat) => assert_eq!(stat.as_slice(), "x = 1"),
+            _ => fail!("expected -e"),
         }
+        match opts.actions.as_slice()[1] {
+            Require(ref name) => assert_eq!(name.as_slice(), "foo"),
+            _ => fail!("expected -l"),
+        }
+
+        // Options after `--` or `-` aren't handled.
+        assert_eq!(collect_args(args(["rlua", "--", "-v"]).as_slice()).unwrap().script, Some(2));
+        assert_eq!(collect_args(args(["rlua", "-", "-v"]).as_slice()).unwrap().script, Some(1));
+        assert_eq!(collect_args(args(["rlua", "--"]).as_slice()).unwrap().script, None);
+
+        let opts = collect_args(args(["rlua", "--profile", "out.folded", "script.lua"]).as_slice()).unwrap();
+        assert_eq!(opts.profile, Some("out.folded".to_string()));
+        assert_eq!(opts.script, Some(3));
+    }
+
+    #[test]
+    fn test_collect_args_errors()
+    {
+        assert_eq!(collect_args(args(["rlua", "-e"]).as_slice()).err(), Some("-e".to_string()));
+        assert_eq!(collect_args(args(["rlua", "-l", "-i"]).as_slice()).err(), Some("-l".to_string()));
+        assert_eq!(collect_args(args(["rlua", "--coverage"]).as_slice()).err(), Some("--coverage".to_string()));
+        assert_eq!(collect_args(args(["rlua", "-x"]).as_slice()).err(), Some("-x".to_string()));
+    }
+
+    #[test]
+    fn test_check_hooks()
+    {
+        let opts = collect_args(args(["rlua", "--profile", "p", "--coverage", "c", "script.lua"]).as_slice()).unwrap();
+        assert!(check_hooks(&opts).is_some());
+        let opts = collect_args(args(["rlua", "--debug"]).as_slice()).unwrap();
+        assert!(check_hooks(&opts).is_some());
+        let opts = collect_args(args(["rlua", "--coverage", "c", "script.lua"]).as_slice()).unwrap();
+        assert!(check_hooks(&opts).is_none());
+    }
+
+    #[test]
+    fn test_handle_script()
+    {
+        let dir = TempDir::new("rlua").unwrap();
+        let script = dir.path().join("script.lua");
+        File::create(&script).write(bytes!("#!/usr/bin/env rlua\n\
+                                            n, first = select('#', ...), ...\n\
+                                            name, interpreter, option = arg[0], arg[-2], arg[-1]")).unwrap();
+        let script = script.as_str().unwrap();
+
+        let lua = Lua::new();
+        lua.state.load_stdlibs();
+        let status = handle_script(&lua, args(["rlua", "-E", script, "a", "b"]).as_slice(), 2);
+        assert_eq!(status, LuaOk);
+        assert_eq!(lua.get_global("n"), Some(2));
+        assert_eq!(lua.get_global("first"), Some("a".to_string()));
+        assert_eq!(lua.get_global("name"), Some(script.to_string()));
+        assert_eq!(lua.get_global("interpreter"), Some("rlua".to_string()));
+        assert_eq!(lua.get_global("option"), Some("-E".to_string()));
+    }
+
+    #[test]
+    fn test_docall_traceback()
+    {
+        let lua = Lua::new();
+        lua.state.load_stdlibs();
+        assert_eq!(lua.state.load_str("local function f() error('boom') end f()"), LuaOk);
+        assert_eq!(docall(&lua, 0, 0), LuaErr(RuntimeError));
+
+        let msg: String = lua.peek().unwrap();
+        assert!(msg.as_slice().contains("boom"));
+        assert!(msg.as_slice().contains("stack traceback:"));
+
+        // The message is printed and popped.
+        let top = lua.state.get_top();
+        assert_eq!(report(&lua, LuaErr(RuntimeError)), LuaErr(RuntimeError));
+        assert_eq!(lua.state.get_top(), top - 1);
+    }
+
+    #[test]
+    fn test_docall_error_object()
+    {
+        let lua = Lua::new();
+        lua.state.load_stdlibs();
+        assert_eq!(lua.state.load_str("error(setmetatable({}, {__tostring = function() return 'custom' end}))"), LuaOk);
+        assert_eq!(docall(&lua, 0, 0), LuaErr(RuntimeError));
+        assert_eq!(lua.pop(), Some("custom".to_string()));
+
+        assert_eq!(lua.state.load_str("error({})"), LuaOk);
+        assert_eq!(docall(&lua, 0, 0), LuaErr(RuntimeError));
+        assert_eq!(lua.pop(), Some("(no error message)".to_string()));
+
+        // Results are kept on success.
+        assert_eq!(lua.state.load_str("return 1, 2"), LuaOk);
+        assert_eq!(docall(&lua, 0, -1), LuaOk);
+        assert_eq!(lua.state.get_top(), 2);
+        assert_eq!(report(&lua, LuaOk), LuaOk);
+        assert_eq!(lua.state.get_top(), 2);
     }
 }
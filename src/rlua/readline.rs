@@ -0,0 +1,98 @@
+// Line editing and history through GNU readline when built with `--cfg use_readline`
+// (like `LUA_USE_READLINE` for `lua.c`), plain standard input otherwise.
+
+#[cfg(use_readline)]
+pub use self::gnu::{read_line, add_to_history, load_history, save_history};
+#[cfg(not(use_readline))]
+pub use self::stdin::{read_line, add_to_history, load_history, save_history};
+
+#[cfg(use_readline)]
+mod gnu {
+    use std::str::raw;
+
+    use libc::{c_char, c_int, c_void, free};
+
+    #[link(name = "readline")]
+    extern {
+        fn readline(prompt: *c_char) -> *c_char;
+        fn add_history(line: *c_char);
+        fn read_history(filename: *c_char) -> c_int;
+        fn write_history(filename: *c_char) -> c_int;
+    }
+
+    /// Read a line with editing support, or None at end of input.
+    pub fn read_line(prompt: &str) -> Option<String> {
+        prompt.with_c_str(|prompt| unsafe {
+            let line = readline(prompt);
+            match line.is_null() {
+                true => None,
+                false => {
+                    let s = raw::from_c_str(line);
+                    free(line as *c_void);
+                    Some(s)
+                }
+            }
+        })
+    }
+
+    /// Add a line to the history.
+    pub fn add_to_history(line: &str) {
+        if line.is_empty() {
+            return;
+        }
+
+        line.with_c_str(|line| unsafe {
+            add_history(line);
+        });
+    }
+
+    /// Load the history from a file.
+    pub fn load_history(filename: &Path) -> bool {
+        filename.with_c_str(|filename| unsafe {
+            read_history(filename) == 0
+        })
+    }
+
+    /// Save the history to a file.
+    pub fn save_history(filename: &Path) -> bool {
+        filename.with_c_str(|filename| unsafe {
+            write_history(filename) == 0
+        })
+    }
+}
+
+#[cfg(not(use_readline))]
+mod stdin {
+    use std::io;
+
+    /// Read a line from stdin, or None at end of input.
+    pub fn read_line(prompt: &str) -> Option<String> {
+        print!("{}", prompt);
+        io::stdio::flush();
+
+        // Unbuffered, so nothing is lost if stdin is also read by scripts.
+        let mut stdin = io::stdio::stdin_raw();
+        let mut line = Vec::new();
+        loop {
+            match stdin.read_byte() {
+                Ok(c) if c == '\n' as u8 => break,
+                Ok(c) => line.push(c),
+                Err(_) if line.is_empty() => return None,
+                Err(_) => break,
+            }
+        }
+        String::from_utf8(line)
+    }
+
+    /// No history without readline.
+    pub fn add_to_history(_: &str) {
+    }
+
+    pub fn load_history(_: &Path) -> bool {
+        false
+    }
+
+    pub fn save_history(_: &Path) -> bool {
+        false
+    }
+}
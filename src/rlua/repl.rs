@@ -0,0 +1,246 @@
+use std::os;
+
+use lua::Lua;
+use lua::ffi;
+use lua::load::Text;
+use lua::pretty::{PrettyOptions, to_source};
+use lua::status::{LuaStatus, LuaOk, LuaErr, SyntaxError};
+
+use readline;
+use {docall, report};
+
+/// Mark at the end of syntax errors caused by incomplete statements.
+static EOF_MARK: &'static str = "<eof>";
+
+static HISTORY_FILE: &'static str = ".rlua_history";
+
+/**
+ *  Run an interactive loop, like `lua.c`.
+ *
+ *  Expressions results are printed, lines starting with `=` are evaluated as `return ...`,
+ *  and incomplete statements are continued on the next lines.
+ */
+pub fn run(lua: &Lua) {
+    let history = os::homedir().map(|home| home.join(HISTORY_FILE));
+    match history {
+        Some(ref history) => {
+            readline::load_history(history);
+        }
+        None => {}
+    }
+
+    loop {
+        let status = match load_line(lua) {
+            Some(status) => status,
+            None => break,
+        };
+
+        let status = match status {
+            LuaOk => docall(lua, 0, ffi::LUA_MULTRET as int),
+            status => status,
+        };
+
+        match report(lua, status) {
+            LuaOk => print_results(lua),
+            LuaErr(_) => {}
+        }
+        lua.state.set_top(0);
+    }
+
+    match history {
+        Some(ref history) => {
+            readline::save_history(history);
+        }
+        None => {}
+    }
+    println!("");
+}
+
+fn prompt(lua: &Lua, first: bool) -> String {
+    let (name, default) = match first {
+        true => ("_PROMPT", "> "),
+        false => ("_PROMPT2", ">> "),
+    };
+
+    // Like `lua_tostring`, other values than strings and numbers aren't converted.
+    lua.state.get_global(name);
+    let prompt = match lua.state.get_type(-1) {
+        ::lua::types::LuaString | ::lua::types::LuaNumber => lua.state.get_str(-1),
+        _ => default.to_string(),
+    };
+    lua.state.pop(1);
+    prompt
+}
+
+fn load(lua: &Lua, source: &str) -> LuaStatus {
+    lua.state.load_buffer(source.as_bytes(), "=stdin", Text)
+}
+
+/// Whether a load status is a syntax error caused by an incomplete statement.
+fn incomplete(lua: &Lua, status: LuaStatus) -> bool {
+    match status {
+        LuaErr(SyntaxError) => {
+            let msg: String = lua.peek().unwrap_or(String::new());
+            msg.as_slice().ends_with(EOF_MARK)
+        }
+        _ => false,
+    }
+}
+
+/**
+ *  Read and compile a complete statement, pushing the chunk or the error message.
+ *
+ *  Return None at end of input.
+ */
+fn load_line(lua: &Lua) -> Option<LuaStatus> {
+    let mut line = match readline::read_line(prompt(lua, true).as_slice()) {
+        Some(line) => line,
+        None => return None,
+    };
+
+    if line.as_slice().starts_with("=") {
+        line = format!("return {}", line.as_slice().slice_from(1));
+    } else {
+        // Try the line as an expression first, to print its value.
+        match load(lua, format!("return {}", line).as_slice()) {
+            LuaOk => {
+                readline::add_to_history(line.as_slice());
+                return Some(LuaOk);
+            }
+            _ => lua.state.pop(1),
+        }
+    }
+
+    loop {
+        let status = load(lua, line.as_slice());
+        if !incomplete(lua, status) {
+            readline::add_to_history(line.as_slice());
+            return Some(status);
+        }
+
+        match readline::read_line(prompt(lua, false).as_slice()) {
+            Some(more) => {
+                lua.state.pop(1);
+                line = format!("{}\n{}", line, more);
+            }
+            // Report the incomplete statement error.
+            None => return Some(status),
+        }
+    }
+}
+
+/// Print every value on the stack, if any.
+fn print_results(lua: &Lua) {
+    if lua.state.get_top() == 0 {
+        return;
+    }
+    match report(lua, format_results(lua)) {
+        LuaOk => println!("{}", lua.state.get_str(-1)),
+        LuaErr(_) => {}
+    }
+}
+
+/**
+ *  Replace the values on the stack by their formatting, or by an error message.
+ *
+ *  Like `lua.c` calling `print`, values are formatted in protected mode: `__tostring`
+ *  metamethods may raise errors.
+ */
+fn format_results(lua: &Lua) -> LuaStatus {
+    let n = lua.state.get_top();
+    lua.state.push_function(format_values);
+    lua.state.insert(1);
+    lua.state.pcall(n, 1, 0)
+}
+
+/**
+ *  Push the formatting of every value on the stack as Lua source, tables indented and keys sorted.
+ *
+ *  Functions, userdata, threads and values with a `__tostring` metamethod are formatted like `tostring`.
+ */
+fn format_values(lua: &Lua) -> int {
+    let mut options = PrettyOptions::new();
+    options.indent = Some("  ".to_string());
+    options.sort_keys = true;
+    options.strict = false;
+    options.display = true;
+
+    let results: Vec<String> = range(1, lua.state.get_top() + 1).map(|i| {
+        if has_tostring(lua, i) {
+            return lua.state.to_display_str(i);
+        }
+        match to_source(&lua.state, i, &options) {
+            Ok(source) => source,
+            Err(msg) => format!("--[[{}]]", msg),
+        }
+    }).collect();
+    lua.state.push_str(results.connect("\t").as_slice());
+    1
+}
+
+/// Whether the value at the given index has a `__tostring` metamethod.
+fn has_tostring(lua: &Lua, idx: int) -> bool {
+    if !lua.state.get_metatable(idx) {
+        return false;
+    }
+    lua.state.get_field(-1, "__tostring");
+    let found = match lua.state.get_type(-1) {
+        ::lua::types::LuaNil => false,
+        _ => true,
+    };
+    lua.state.pop(2);
+    found
+}
+
+#[cfg(test)]
+mod test {
+    use lua::Lua;
+    use lua::status::{LuaOk, LuaErr, RuntimeError};
+
+    use super::format_results;
+
+    /// Format the values returned by `source`.
+    fn format(lua: &Lua, source: &str) -> String {
+        assert_eq!(lua.exec_str(source), LuaOk);
+        assert_eq!(format_results(lua), LuaOk);
+        let results = lua.state.get_str(-1);
+        lua.state.set_top(0);
+        results
+    }
+
+    #[test]
+    fn test_format_results()
+    {
+        let lua = Lua::new();
+        lua.state.load_stdlibs();
+        let results = format(&lua, "
+            local point = setmetatable({}, {__tostring = function() return 'point' end})
+            return 1, 'a', {x = 2}, point, true, nil
+        ");
+        assert_eq!(results.as_slice(), "1\t\"a\"\t{\n  x = 2\n}\tpoint\ttrue\tnil");
+
+        let results = format(&lua, "return print, {f = print}");
+        let results: Vec<&str> = results.as_slice().split('\t').collect();
+        assert!(results.get(0).starts_with("function: "));
+        assert!(results.get(1).starts_with("{\n  f = function: "));
+    }
+
+    #[test]
+    fn test_format_results_error()
+    {
+        let lua = Lua::new();
+        lua.state.load_stdlibs();
+        assert_eq!(lua.exec_str("
+            return setmetatable({}, {__tostring = function() error('no tostring', 0) end})
+        "), LuaOk);
+        assert_eq!(format_results(&lua), LuaErr(RuntimeError));
+        assert_eq!(lua.state.get_str(-1).as_slice(), "no tostring");
+        lua.state.set_top(0);
+
+        let results = format(&lua, "
+            debug.setmetatable(print, {__tostring = function() error('no tostring', 0) end})
+            return {f = print}
+        ");
+        assert_eq!(results.as_slice(), "--[[no tostring]]");
+    }
+}